@@ -0,0 +1,693 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::bail;
+use toml_edit::{Array, ImDocument, Item, Key};
+
+use crate::model::{
+    PathVarConfig, ProjectDesc, ScriptConfig, SecretsConfig, ServiceConfig, ShellConfig,
+};
+use crate::recipe::recipe_names;
+use crate::ser::struct_fields;
+
+/// The top level names a template can refer to, e.g. `{vars.NAME}`.
+const TEMPLATE_ROOTS: &[&str] = &[
+    "state_dir",
+    "project_dir",
+    "pkgs",
+    "vars",
+    "ports",
+    "params",
+    "service",
+];
+const DEPENDENCY_FIELDS: &[&str] = &["name", "path"];
+const SERVICE_FIELDS: &[&str] = &["name", "dir", "port"];
+
+/// Where a template is used, which decides what else it can refer to.
+#[derive(Clone)]
+enum Scope<'a> {
+    Project,
+    /// A script, along with the parameters it declares
+    Script(HashSet<&'a str>),
+    Service {
+        has_port: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+    fn error(message: impl Into<String>, span: Option<Range<usize>>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+        }
+    }
+
+    fn warning(message: impl Into<String>, span: Option<Range<usize>>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Validates the contents of a project file without touching the system.
+pub fn check_project(contents: &str) -> Vec<Diagnostic> {
+    let document = match ImDocument::parse(contents) {
+        Ok(document) => document,
+        Err(e) => return vec![Diagnostic::error(e.message(), e.span())],
+    };
+    let root = document.as_item();
+
+    let mut diagnostics = Vec::new();
+    check_keys(root, struct_fields::<ProjectDesc>(), &mut diagnostics);
+
+    if let Some(shell) = root.get("shell") {
+        check_keys(shell, struct_fields::<ShellConfig>(), &mut diagnostics);
+        if let Some(paths) = shell.get("userPaths").and_then(Item::as_array) {
+            check_duplicate_paths(paths, &mut diagnostics);
+        }
+    }
+
+    for (_, path_var) in entries(root.get("paths")) {
+        check_keys(path_var, struct_fields::<PathVarConfig>(), &mut diagnostics);
+        if let Some(paths) = path_var.get("entries").and_then(Item::as_array) {
+            check_duplicate_paths(paths, &mut diagnostics);
+        }
+    }
+
+    for (name, port) in entries(root.get("ports")) {
+        let valid = match port.as_integer() {
+            Some(port) => (1..=u16::MAX as i64).contains(&port),
+            None => port.as_str() == Some("auto"),
+        };
+        if !valid {
+            diagnostics.push(Diagnostic::error(
+                format!(
+                    "invalid port `{}`, expected a port number or \"auto\"",
+                    name.get()
+                ),
+                port.span(),
+            ));
+        }
+    }
+
+    if let Some(secrets) = root.get("secrets") {
+        check_keys(secrets, struct_fields::<SecretsConfig>(), &mut diagnostics);
+    }
+
+    let service_names: Vec<&str> = entries(root.get("services"))
+        .into_iter()
+        .map(|(k, _)| k.get())
+        .collect();
+    for (_, members) in entries(root.get("groups")) {
+        check_names(Some(members), &service_names, "service", &mut diagnostics);
+    }
+
+    let mut exported = HashSet::new();
+    for (name, service) in entries(root.get("services")) {
+        for (export, _) in entries(service.get("exports")) {
+            if !exported.insert(export.get()) {
+                diagnostics.push(Diagnostic::warning(
+                    format!("`{}` is exported by more than one service", export.get()),
+                    export.span(),
+                ));
+            }
+        }
+
+        if !is_valid_service_name(name.get()) {
+            diagnostics.push(Diagnostic::error(
+                format!(
+                    "invalid service name `{}`, only letters, digits, `-` and `_` are allowed",
+                    name.get()
+                ),
+                name.span(),
+            ));
+        }
+
+        check_keys(service, struct_fields::<ServiceConfig>(), &mut diagnostics);
+        check_names(
+            service.get("depends"),
+            &service_names,
+            "service",
+            &mut diagnostics,
+        );
+
+        match service.get("recipe") {
+            Some(recipe) => {
+                let known = recipe_names();
+                match recipe.as_str() {
+                    Some(r) if !known.contains(&r) => {
+                        let message = match suggest(r, &known) {
+                            Some(suggestion) => {
+                                format!("unknown recipe `{r}`, did you mean `{suggestion}`?")
+                            }
+                            None => format!(
+                                "unknown recipe `{r}`, available recipes are {}",
+                                known.join(", ")
+                            ),
+                        };
+                        diagnostics.push(Diagnostic::error(message, recipe.span()));
+                    }
+                    _ => {}
+                }
+            }
+            None if service.is_table_like() && service.get("script").is_none() => {
+                diagnostics.push(Diagnostic::error(
+                    format!("service `{}` needs either a script or a recipe", name.get()),
+                    name.span(),
+                ));
+            }
+            None => {
+                if let Some((key, _)) = service
+                    .as_table_like()
+                    .and_then(|t| t.get_key_value("version"))
+                {
+                    diagnostics.push(Diagnostic::warning(
+                        "`version` only applies to services using a recipe",
+                        key.span(),
+                    ));
+                }
+            }
+        }
+
+        if service.get("kind").and_then(Item::as_str) == Some("oneshot") {
+            if let Some((key, _)) = service
+                .as_table_like()
+                .and_then(|t| t.get_key_value("ready"))
+            {
+                diagnostics.push(Diagnostic::warning(
+                    "`ready` doesn't apply to oneshot services, which are ready once they complete",
+                    key.span(),
+                ));
+            }
+        }
+
+        if let Some(script) = service.get("script") {
+            check_not_empty(
+                script,
+                &format!("script of service `{}`", name.get()),
+                &mut diagnostics,
+            );
+        }
+    }
+
+    let script_names: Vec<&str> = entries(root.get("scripts"))
+        .into_iter()
+        .map(|(k, _)| k.get())
+        .collect();
+    for (name, script) in entries(root.get("scripts")) {
+        let body = if script.is_table_like() {
+            check_keys(script, struct_fields::<ScriptConfig>(), &mut diagnostics);
+            check_names(
+                script.get("depends"),
+                &script_names,
+                "script",
+                &mut diagnostics,
+            );
+            script.get("script")
+        } else {
+            Some(script)
+        };
+
+        if let Some(body) = body {
+            check_not_empty(body, &format!("script `{}`", name.get()), &mut diagnostics);
+        }
+    }
+
+    check_templates(root, &mut diagnostics);
+
+    if !diagnostics.iter().any(Diagnostic::is_error) {
+        if let Err(e) = toml::from_str::<ProjectDesc>(contents) {
+            diagnostics.push(Diagnostic::error(e.message(), e.span()));
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.span.as_ref().map(|s| s.start));
+    diagnostics
+}
+
+/// Lists the keys and values of a table, or nothing if the item isn't one.
+fn entries(item: Option<&Item>) -> Vec<(&Key, &Item)> {
+    item.and_then(Item::as_table_like)
+        .map(|table| {
+            table
+                .iter()
+                .filter_map(|(k, _)| table.get_key_value(k))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn check_keys(table: &Item, known: &[&str], diagnostics: &mut Vec<Diagnostic>) {
+    for (key, _) in entries(Some(table)) {
+        if known.contains(&key.get()) {
+            continue;
+        }
+
+        let message = match suggest(key.get(), known) {
+            Some(suggestion) => {
+                format!("unknown key `{}`, did you mean `{suggestion}`?", key.get())
+            }
+            None => format!(
+                "unknown key `{}`, expected one of {}",
+                key.get(),
+                known
+                    .iter()
+                    .map(|k| format!("`{k}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        diagnostics.push(Diagnostic::error(message, key.span()));
+    }
+}
+
+/// Checks that the items of an array name one of `known`, which are of the given kind.
+fn check_names(
+    names: Option<&Item>,
+    known: &[&str],
+    kind: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for name in names.and_then(Item::as_array).into_iter().flatten() {
+        match name.as_str() {
+            Some(n) if !known.contains(&n) => {
+                let message = match suggest(n, known) {
+                    Some(suggestion) => {
+                        format!("unknown {kind} `{n}`, did you mean `{suggestion}`?")
+                    }
+                    None => format!("unknown {kind} `{n}`"),
+                };
+                diagnostics.push(Diagnostic::error(message, name.span()));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn suggest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (strsim::levenshtein(key, c), *c))
+        .filter(|(distance, c)| *distance <= (c.len() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, c)| c)
+}
+
+fn is_valid_service_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn check_not_empty(value: &Item, what: &str, diagnostics: &mut Vec<Diagnostic>) {
+    if value.as_str().is_some_and(|s| s.trim().is_empty()) {
+        diagnostics.push(Diagnostic::warning(
+            format!("{what} is empty"),
+            value.span(),
+        ));
+    }
+}
+
+fn check_duplicate_paths(paths: &Array, diagnostics: &mut Vec<Diagnostic>) {
+    let mut seen = HashSet::new();
+    for path in paths {
+        if let Some(s) = path.as_str() {
+            if !seen.insert(s.trim_end_matches('/')) {
+                diagnostics.push(Diagnostic::warning(
+                    format!("duplicate PATH entry `{s}`"),
+                    path.span(),
+                ));
+            }
+        }
+    }
+}
+
+fn check_templates(root: &Item, diagnostics: &mut Vec<Diagnostic>) {
+    let names = |section: &str| -> HashSet<&str> {
+        entries(root.get(section))
+            .into_iter()
+            .map(|(k, _)| k.get())
+            .collect()
+    };
+    let mut pkgs = names("dependencies");
+    let vars = names("vars");
+    let mut ports = names("ports");
+
+    // Recipes add their formula and a port named after the service
+    for (name, service) in entries(root.get("services")) {
+        if let Some(recipe) = service.get("recipe").and_then(Item::as_str) {
+            pkgs.insert(recipe);
+            ports.insert(name.get());
+        }
+    }
+
+    let mut items = Vec::new();
+    items.extend(
+        entries(root.get("env"))
+            .into_iter()
+            .map(|(_, v)| (v, Scope::Project)),
+    );
+    for (_, script) in entries(root.get("scripts")) {
+        if script.is_table_like() {
+            let params = entries(script.get("params"));
+            items.extend(params.iter().map(|(_, v)| (*v, Scope::Project)));
+            let params: HashSet<&str> = params.into_iter().map(|(k, _)| k.get()).collect();
+
+            for item in ["script", "cwd", "args", "inputs"]
+                .into_iter()
+                .filter_map(|key| script.get(key))
+            {
+                items.push((item, Scope::Script(params.clone())));
+            }
+        } else {
+            items.push((script, Scope::Script(HashSet::new())));
+        }
+    }
+    items.extend(
+        root.get("shell")
+            .and_then(|s| s.get("hook"))
+            .map(|hook| (hook, Scope::Project)),
+    );
+    for (name, service) in entries(root.get("services")) {
+        let scope = Scope::Service {
+            has_port: ports.contains(name.get()),
+        };
+        for item in ["script", "init", "ready", "stop", "watch", "ignore"]
+            .into_iter()
+            .filter_map(|key| service.get(key))
+        {
+            items.push((item, scope.clone()));
+        }
+        for (_, value) in entries(service.get("env"))
+            .into_iter()
+            .chain(entries(service.get("exports")))
+        {
+            items.push((value, scope.clone()));
+        }
+    }
+    items.extend(
+        entries(root.get("paths"))
+            .into_iter()
+            .filter_map(|(_, p)| p.get("entries"))
+            .chain(root.get("shell").and_then(|s| s.get("userPaths")))
+            .map(|array| (array, Scope::Project)),
+    );
+
+    let mut templates = Vec::new();
+    for (item, scope) in items {
+        match item.as_array() {
+            Some(array) => templates.extend(
+                array
+                    .iter()
+                    .filter_map(|v| Some((v.as_str()?, v.span(), scope.clone()))),
+            ),
+            None => templates.extend(item.as_str().map(|s| (s, item.span(), scope))),
+        }
+    }
+
+    for (template, span, scope) in templates {
+        for reference in template_references(template) {
+            let mut segments = reference.split('.');
+            let root = segments.next().unwrap_or_default();
+            let problem = match (root, segments.next(), segments.next()) {
+                (r, ..) if r.starts_with('@') => None,
+                ("pkgs" | "vars" | "ports" | "params" | "service", None, _) => Some(format!(
+                    "`{reference}` is a table, refer to one of its entries instead"
+                )),
+                ("params", name, _) => match (&scope, name) {
+                    (Scope::Script(params), Some(name)) if !params.contains(name) => Some(
+                        format!("`{reference}` refers to undeclared parameter `{name}`"),
+                    ),
+                    (Scope::Script(_), _) => None,
+                    _ => Some(format!(
+                        "`{reference}` can only be used in scripts that declare parameters"
+                    )),
+                },
+                ("service", field, _) => match (&scope, field) {
+                    (Scope::Service { has_port: false }, Some("port")) => Some(format!(
+                        "`{reference}` refers to a port named after the service, which isn't in ports"
+                    )),
+                    (Scope::Service { .. }, Some(field)) if !SERVICE_FIELDS.contains(&field) => {
+                        Some(format!("unknown service field `{field}` in `{reference}`"))
+                    }
+                    (Scope::Service { .. }, _) => None,
+                    _ => Some(format!("`{reference}` can only be used in services")),
+                },
+                (r, ..) if !TEMPLATE_ROOTS.contains(&r) => {
+                    Some(match suggest(r, TEMPLATE_ROOTS) {
+                        Some(suggestion) => {
+                            format!("unknown template variable `{reference}`, did you mean `{suggestion}`?")
+                        }
+                        None => format!("unknown template variable `{reference}`"),
+                    })
+                }
+                ("vars", Some(name), _) if !vars.contains(name) => {
+                    Some(format!("`{reference}` refers to undefined var `{name}`"))
+                }
+                ("ports", Some(name), _) if !ports.contains(name) => {
+                    Some(format!("`{reference}` refers to undefined port `{name}`"))
+                }
+                ("pkgs", Some(name), _) if !pkgs.contains(name) => Some(format!(
+                    "`{reference}` refers to `{name}` which is not listed in dependencies"
+                )),
+                ("pkgs", Some(_), None) => Some(format!(
+                    "`{reference}` needs a dependency field, one of {}",
+                    DEPENDENCY_FIELDS.join(", ")
+                )),
+                ("pkgs", Some(_), Some(field)) if !DEPENDENCY_FIELDS.contains(&field) => Some(
+                    format!("unknown dependency field `{field}` in `{reference}`"),
+                ),
+                _ => None,
+            };
+
+            if let Some(problem) = problem {
+                diagnostics.push(Diagnostic::error(problem, span.clone()));
+            }
+        }
+    }
+}
+
+/// Extracts the value paths referred to by `{...}` expressions in a template. Block tags such as
+/// `{{ if ... }}` and escaped braces are skipped.
+fn template_references(tpl: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+    let mut rest = tpl;
+
+    while let Some(start) = rest.find('{') {
+        let escaped = rest[..start].ends_with('\\');
+        let after = &rest[start + 1..];
+
+        if escaped {
+            rest = after;
+            continue;
+        }
+
+        if let Some(block) = after.strip_prefix('{') {
+            match block.find("}}") {
+                Some(end) => rest = &block[end + 2..],
+                None => break,
+            }
+            continue;
+        }
+
+        match after.find('}') {
+            Some(end) => {
+                let expr = after[..end].split('|').next().unwrap_or_default().trim();
+                if !expr.is_empty() {
+                    references.push(expr);
+                }
+                rest = &after[end + 1..];
+            }
+            None => break,
+        }
+    }
+
+    references
+}
+
+/// Prints diagnostics to stderr in a `file:line:column` format, along with the offending line.
+pub fn print_diagnostics(path: &Path, contents: &str, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprint!("{}", format_diagnostic(path, contents, diagnostic));
+    }
+}
+
+/// Formats a diagnostic like compilers do, with the line it is about underlined.
+fn format_diagnostic(path: &Path, contents: &str, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+
+    let Some(span) = &diagnostic.span else {
+        return format!("{}: {severity}: {}\n", path.display(), diagnostic.message);
+    };
+
+    let line_start = contents[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = contents[span.start..]
+        .find('\n')
+        .map_or(contents.len(), |i| span.start + i);
+    let line_no = contents[..span.start].matches('\n').count() + 1;
+    let column = contents[line_start..span.start].chars().count() + 1;
+    let underline = contents[span.start..span.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    format!(
+        "{}:{line_no}:{column}: {severity}: {}\n    {}\n    {}{}\n",
+        path.display(),
+        diagnostic.message,
+        &contents[line_start..line_end],
+        " ".repeat(column - 1),
+        "^".repeat(underline)
+    )
+}
+
+/// Implements `devit check`: validates the project file and reports every problem found.
+pub fn check_project_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let diagnostics = check_project(contents);
+    print_diagnostics(path, contents, &diagnostics);
+
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        bail!(
+            "{} has {errors} error(s) and {warnings} warning(s)",
+            path.display()
+        );
+    }
+
+    match warnings {
+        0 => println!("{} looks good", path.display()),
+        _ => println!("{} looks good, with {warnings} warning(s)", path.display()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(contents: &str) -> Vec<String> {
+        check_project(contents)
+            .into_iter()
+            .map(|d| d.message)
+            .collect()
+    }
+
+    #[test]
+    fn suggests_known_keys() {
+        let messages = messages(
+            r#"
+[dependencies]
+
+[services.api]
+scirpt = "run"
+"#,
+        );
+        assert!(messages.contains(&"unknown key `scirpt`, did you mean `script`?".to_string()));
+    }
+
+    #[test]
+    fn reports_unknown_template_variables() {
+        let messages = messages(
+            r#"
+[dependencies]
+
+[scripts]
+build = "make {varz.out}"
+"#,
+        );
+        assert_eq!(
+            messages,
+            ["unknown template variable `varz.out`, did you mean `vars`?".to_string()]
+        );
+    }
+
+    #[test]
+    fn requires_a_dependency_field() {
+        let messages = messages(
+            r#"
+[dependencies]
+foo = "1"
+
+[scripts]
+build = "{pkgs.foo}/bin/foo {pkgs}"
+"#,
+        );
+        assert_eq!(
+            messages,
+            [
+                "`pkgs.foo` needs a dependency field, one of name, path".to_string(),
+                "`pkgs` is a table, refer to one of its entries instead".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_line_and_column() {
+        let contents = "[dependencies]\n\n[scripts]\nbuild = \"make {nope}\"\n";
+        let diagnostics = check_project(contents);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            format_diagnostic(Path::new("devit.toml"), contents, &diagnostics[0]),
+            "devit.toml:4:9: error: unknown template variable `nope`\n    build = \"make {nope}\"\n            ^^^^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn warnings_alone_pass_the_check() {
+        let contents = r#"
+[dependencies]
+
+[services.migrate]
+script = "run"
+kind = "oneshot"
+ready = "true"
+"#;
+        let diagnostics = check_project(contents);
+        assert!(!diagnostics.is_empty());
+        assert!(!diagnostics.iter().any(Diagnostic::is_error));
+        assert!(check_project_file(Path::new("devit.toml"), contents).is_ok());
+    }
+
+    #[test]
+    fn reports_unknown_reference_in_watch() {
+        let messages = messages(
+            r#"
+[dependencies]
+
+[services.api]
+script = "run"
+watch = ["{vars.nope}/**"]
+"#,
+        );
+        assert_eq!(
+            messages,
+            ["`vars.nope` refers to undefined var `nope`".to_string()]
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::supervisor::{Control, Supervisor};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::spawn;
+
+/// The socket a running `devit up` listens on, in the state directory.
+pub const CONTROL_SOCKET: &str = "devit.sock";
+
+/// A request to a running `devit up`, sent as a line of JSON.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Start { services: Vec<String> },
+    Stop { services: Vec<String> },
+    Restart { services: Vec<String> },
+    Status,
+}
+
+/// The answer to a request, sent back as a line of JSON. It lists the services as they were
+/// when the request was received.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub services: Vec<ServiceReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceReport {
+    pub name: String,
+    pub status: String,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub ready: bool,
+}
+
+/// Listens on the control socket of the project. A socket left behind by a `devit up` that
+/// didn't shut down cleanly is replaced, but not one that is still in use.
+pub fn listen(state_dir: &Path) -> anyhow::Result<UnixListener> {
+    let path = state_dir.join(CONTROL_SOCKET);
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            bail!("another devit up is already running for this project");
+        }
+        std::fs::remove_file(&path)
+            .with_context(|| format!("removing stale socket {}", path.display()))?;
+    }
+
+    std::fs::create_dir_all(state_dir).context("creating state dir")?;
+    UnixListener::bind(&path).with_context(|| format!("listening on {}", path.display()))
+}
+
+/// Sends a request to the `devit up` running for the project and waits for its answer.
+pub async fn send_request(state_dir: &Path, request: &Request) -> anyhow::Result<Response> {
+    let path = state_dir.join(CONTROL_SOCKET);
+    let stream = UnixStream::connect(&path)
+        .await
+        .context("Unable to reach devit up, is it running for this project?")?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(request).context("encoding request")?;
+    line.push('\n');
+    writer
+        .write_all(line.as_bytes())
+        .await
+        .context("sending request")?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .context("reading response")?
+        .context("devit up closed the connection")?;
+    let response: Response = serde_json::from_str(&line).context("parsing response")?;
+    if let Some(error) = response.error {
+        bail!(error);
+    }
+    Ok(response)
+}
+
+impl Supervisor {
+    /// Answers the requests coming in on the control socket, for as long as it's polled.
+    pub async fn serve(self, listener: UnixListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            spawn(self.clone().answer(stream));
+        }
+    }
+
+    async fn answer(self, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self.handle(request),
+                Err(e) => Response {
+                    error: Some(format!("Invalid request: {e}")),
+                    ..Default::default()
+                },
+            };
+
+            let Ok(mut line) = serde_json::to_string(&response) else {
+                break;
+            };
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let (control, services) = match request {
+            Request::Start { services } => (Control::Start, services),
+            Request::Stop { services } => (Control::Stop, services),
+            Request::Restart { services } => (Control::Restart, services),
+            Request::Status => return self.report(),
+        };
+
+        // Services of the project that aren't supervised yet can be started on demand
+        let project = self.project();
+        let unknown: Vec<_> = services
+            .iter()
+            .filter(|name| {
+                self.status(name).is_none()
+                    && !(control == Control::Start && project.services.contains_key(*name))
+            })
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Response {
+                error: Some(format!(
+                    "Not running under this devit up: {}",
+                    unknown.join(", ")
+                )),
+                ..Default::default()
+            };
+        }
+
+        let response = self.report();
+        for name in &services {
+            let result = match self.status(name) {
+                Some(_) => self.control(name, control),
+                None => self.start(name),
+            };
+            if let Err(e) = result {
+                return Response {
+                    error: Some(format!("{e:#}")),
+                    ..Default::default()
+                };
+            }
+        }
+        response
+    }
+
+    fn report(&self) -> Response {
+        let services = self.with_states(|states| {
+            states
+                .iter()
+                .map(|(name, state)| ServiceReport {
+                    name: name.clone(),
+                    status: state.status.to_string(),
+                    pid: state.pid,
+                    restarts: state.restarts,
+                    ready: self.is_ready(name),
+                })
+                .collect()
+        });
+
+        Response {
+            error: None,
+            services,
+        }
+    }
+}
+
+/// Implements `devit status`, which prints the services of the running `devit up`.
+pub fn print_status(services: &[ServiceReport]) {
+    let width = services
+        .iter()
+        .map(|service| service.name.len())
+        .chain(["SERVICE".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:width$}  {:8}  {:8}  {:5}  STATUS",
+        "SERVICE", "PID", "RESTARTS", "READY"
+    );
+    for service in services {
+        let pid = service.pid.map(|pid| pid.to_string()).unwrap_or_default();
+        let ready = if service.ready { "yes" } else { "no" };
+        println!(
+            "{:width$}  {pid:8}  {:8}  {ready:5}  {}",
+            service.name, service.restarts, service.status
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{bail, Context};
+
+/// Parses the contents of a `.env` style file into its variables, in order of appearance.
+///
+/// Supports blank lines, `#` comments, an optional `export` prefix, and single (literal) or
+/// double quoted (escaped) values. `${VAR}` and `${VAR:-default}` in unquoted or double quoted
+/// values expand to variables defined earlier in the file, falling back to `lookup`.
+pub fn parse(
+    contents: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected NAME=VALUE", line_no + 1))?;
+
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("line {}: invalid variable name '{name}'", line_no + 1);
+        }
+
+        let value = parse_value(value.trim(), |name| {
+            vars.iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+                .or_else(|| lookup(name))
+        })
+        .with_context(|| format!("line {}", line_no + 1))?;
+        vars.push((name.to_string(), value));
+    }
+
+    Ok(vars)
+}
+
+fn parse_value(value: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let (value, _) = rest.split_once('\'').context("unterminated single quote")?;
+        return Ok(value.to_string());
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut result = Vec::new();
+        let mut chars = rest.chars();
+        loop {
+            match chars.next().context("unterminated double quote")? {
+                '"' => return expand(&result, lookup),
+                '\\' => match chars.next().context("unterminated double quote")? {
+                    'n' => result.push(('\n', true)),
+                    't' => result.push(('\t', true)),
+                    c => result.push((c, true)),
+                },
+                c => result.push((c, false)),
+            }
+        }
+    }
+
+    // Unquoted values end at an inline comment
+    let value = match value.find(" #") {
+        Some(i) => &value[..i],
+        None => value,
+    };
+    let mut result = Vec::new();
+    let mut chars = value.trim_end().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                chars.next();
+                result.push(('$', true));
+            }
+            c => result.push((c, false)),
+        }
+    }
+    expand(&result, lookup)
+}
+
+/// Expands the `${...}` expressions of a value, given as its characters along with whether they
+/// were escaped. Escaped characters are always taken literally.
+fn expand(
+    value: &[(char, bool)],
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut i = 0;
+
+    while i < value.len() {
+        if value[i] != ('$', false) || value.get(i + 1) != Some(&('{', false)) {
+            result.push(value[i].0);
+            i += 1;
+            continue;
+        }
+
+        let start = i + 2;
+        let end = start
+            + value[start..]
+                .iter()
+                .position(|(c, _)| *c == '}')
+                .context("unterminated ${")?;
+        let expr: String = value[start..end].iter().map(|(c, _)| c).collect();
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr.as_str(), None),
+        };
+        match lookup(name).filter(|v| !v.is_empty()) {
+            Some(v) => result.push_str(&v),
+            None => result.push_str(default.unwrap_or_default()),
+        }
+        i = end + 1;
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(contents: &str) -> anyhow::Result<Vec<(String, String)>> {
+        parse(contents, |_| None)
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn skips_comments_and_export() {
+        let parsed = parse_all("# comment\n\nexport A=1\nB=2 # inline\n").unwrap();
+        assert_eq!(parsed, vars(&[("A", "1"), ("B", "2")]));
+    }
+
+    #[test]
+    fn keeps_hashes_in_quoted_values() {
+        let parsed = parse_all("A=\"a # b\"\nB='c # d'\nC=e#f\n").unwrap();
+        assert_eq!(
+            parsed,
+            vars(&[("A", "a # b"), ("B", "c # d"), ("C", "e#f")])
+        );
+    }
+
+    #[test]
+    fn unescapes_double_quoted_values() {
+        let parsed = parse_all(r#"A="say \"hi\"\n\$HOME""#).unwrap();
+        assert_eq!(parsed, vars(&[("A", "say \"hi\"\n$HOME")]));
+    }
+
+    #[test]
+    fn expands_after_escaped_backslashes() {
+        let parsed = parse_all("X=val\nY=\"a\\\\${X}\"\nZ=\"a\\${X}\"\n").unwrap();
+        assert_eq!(
+            parsed,
+            vars(&[("X", "val"), ("Y", r"a\val"), ("Z", "a${X}")])
+        );
+    }
+
+    #[test]
+    fn keeps_single_quoted_values_literal() {
+        let parsed = parse_all(r"A='\n${B}'").unwrap();
+        assert_eq!(parsed, vars(&[("A", r"\n${B}")]));
+    }
+
+    #[test]
+    fn accepts_empty_values() {
+        let parsed = parse_all("A=\nB=\"\"\nC=''\n").unwrap();
+        assert_eq!(parsed, vars(&[("A", ""), ("B", ""), ("C", "")]));
+    }
+
+    #[test]
+    fn expands_earlier_variables_and_defaults() {
+        let parsed = parse("A=1\nB=${A}-${C:-3}-${HOME}\n", |name| {
+            (name == "HOME").then(|| "/home".to_string())
+        })
+        .unwrap();
+        assert_eq!(parsed, vars(&[("A", "1"), ("B", "1-3-/home")]));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for contents in [
+            "JUST_A_NAME",
+            "=value",
+            "BAD-NAME=1",
+            "A=\"unterminated",
+            "A='unterminated",
+            "A=${B",
+        ] {
+            assert!(
+                parse_all(contents).is_err(),
+                "{contents} should be rejected"
+            );
+        }
+    }
+}
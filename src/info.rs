@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::model::{PathVar, ProjectEnvironment, Script, ServiceKind};
+
+/// Bumped whenever a field of [ProjectInfo] is renamed or removed.
+const INFO_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum InfoFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+/// The document printed by `devit info`. Unlike [ProjectEnvironment], its shape is part of the
+/// command line interface and is meant for scripting.
+#[derive(Serialize)]
+struct ProjectInfo<'a> {
+    version: u32,
+    project_dir: &'a Path,
+    state_dir: &'a Path,
+    source_files: &'a [PathBuf],
+    dependencies: BTreeMap<&'a str, DependencyDoc<'a>>,
+    vars: BTreeMap<&'a str, &'a str>,
+    ports: BTreeMap<&'a str, u16>,
+    env: BTreeMap<&'a str, &'a str>,
+    /// Only the names, values are never printed
+    secrets: BTreeSet<&'a str>,
+    path_env: BTreeMap<&'a str, &'a PathVar>,
+    scripts: BTreeMap<&'a str, &'a Script>,
+    services: BTreeMap<&'a str, ServiceDoc<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shell_hook: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct DependencyDoc<'a> {
+    formula: &'a str,
+    prefix: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ServiceDoc<'a> {
+    script: &'a Script,
+    kind: ServiceKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    init: Option<&'a Script>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready: Option<&'a Script>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a Script>,
+    env: BTreeMap<&'a str, &'a str>,
+    secrets: BTreeSet<&'a str>,
+    working_directory: &'a Path,
+}
+
+impl<'a> From<&'a ProjectEnvironment> for ProjectInfo<'a> {
+    fn from(project: &'a ProjectEnvironment) -> Self {
+        let sorted = |map: &'a HashMap<String, String>| {
+            map.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        Self {
+            version: INFO_VERSION,
+            project_dir: &project.project_dir,
+            state_dir: &project.state_dir,
+            source_files: &project.source_files,
+            dependencies: project
+                .dependencies
+                .iter()
+                .map(|(key, info)| {
+                    (
+                        key.as_str(),
+                        DependencyDoc {
+                            formula: &info.name,
+                            prefix: &info.path,
+                            version: installed_version(&info.path),
+                        },
+                    )
+                })
+                .collect(),
+            vars: project
+                .vars
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            ports: project
+                .ports
+                .iter()
+                .map(|(k, v)| (k.as_str(), *v))
+                .collect(),
+            env: sorted(&project.user_environ),
+            secrets: project.secrets.keys().map(String::as_str).collect(),
+            path_env: project
+                .environ
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect(),
+            scripts: project
+                .scripts
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect(),
+            services: project
+                .services
+                .iter()
+                .map(|(name, service)| {
+                    (
+                        name.as_str(),
+                        ServiceDoc {
+                            script: &service.script,
+                            kind: service.kind,
+                            init: service.init.as_ref(),
+                            ready: service.ready.as_ref(),
+                            stop: service.stop.as_ref(),
+                            env: sorted(&service.environ),
+                            secrets: service.secrets.keys().map(String::as_str).collect(),
+                            working_directory: &service.working_directory,
+                        },
+                    )
+                })
+                .collect(),
+            shell_hook: project.shell_hook.as_deref(),
+        }
+    }
+}
+
+/// Homebrew prefixes are symlinks into the Cellar, e.g. `opt/postgresql@15` points to
+/// `Cellar/postgresql@15/15.4`, so the installed version is the name of the link target.
+fn installed_version(prefix: &Path) -> Option<String> {
+    let target = std::fs::canonicalize(prefix).ok()?;
+    if target == prefix {
+        return None;
+    }
+
+    Some(target.file_name()?.to_str()?.to_string())
+}
+
+fn render(value: &impl Serialize, format: InfoFormat) -> anyhow::Result<String> {
+    match format {
+        InfoFormat::Json => serde_json::to_string_pretty(value).context("writing json"),
+        InfoFormat::Toml => toml::to_string_pretty(value).context("writing toml"),
+        InfoFormat::Yaml => serde_yaml::to_string(value).context("writing yaml"),
+    }
+}
+
+/// Drops the null fields of objects, which TOML has no way to represent.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+pub fn print_info(
+    project: &ProjectEnvironment,
+    format: InfoFormat,
+    field: Option<&str>,
+) -> anyhow::Result<()> {
+    let info = ProjectInfo::from(project);
+
+    let output = match field {
+        None => render(&info, format)?,
+        Some(field) => {
+            let mut value = serde_json::to_value(&info).context("serializing project info")?;
+            for segment in field.split('.') {
+                value = match value {
+                    Value::Object(mut map) => map.remove(segment),
+                    Value::Array(mut items) => segment
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|i| *i < items.len())
+                        .map(|i| items.swap_remove(i)),
+                    _ => None,
+                }
+                .with_context(|| format!("field '{field}' does not exist"))?;
+            }
+
+            // Scalars are printed as is so they can be used directly in shell scripts
+            match value {
+                Value::String(s) => s,
+                Value::Number(_) | Value::Bool(_) => value.to_string(),
+                Value::Null => String::new(),
+                // TOML documents must be tables, so other values are put under their own name
+                _ if matches!(format, InfoFormat::Toml) => {
+                    let value = match without_nulls(value) {
+                        Value::Object(map) => Value::Object(map),
+                        value => {
+                            let name = field.rsplit('.').next().unwrap_or(field);
+                            serde_json::json!({ name: value })
+                        }
+                    };
+                    render(&value, format)?
+                }
+                _ => render(&value, format)?,
+            }
+        }
+    };
+
+    println!("{}", output.trim_end());
+    Ok(())
+}
@@ -28,7 +28,6 @@ pub fn init_project(path_to_toml: impl AsRef<Path>) -> anyhow::Result<()> {
     let gitignore = project_dir.join(".gitignore");
     if !has_initialised_gitignore(&gitignore) && git_folder.is_dir() {
         let mut file = File::options()
-            .write(true)
             .append(true)
             .create(true)
             .open(&gitignore)
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap_complete::CompletionCandidate;
+use itertools::Itertools;
+
+use crate::model::{ProjectDesc, ScriptConfig, ScriptSpec};
+
+impl ProjectDesc {
+    /// The scripts along with their descriptions, sorted by name.
+    fn script_descriptions(&self) -> BTreeMap<&str, String> {
+        self.scripts
+            .iter()
+            .flatten()
+            .map(|(name, spec)| {
+                let description = match spec {
+                    ScriptSpec::Inline(_) => String::new(),
+                    ScriptSpec::Full(config) => {
+                        let ScriptConfig {
+                            description,
+                            depends,
+                            ..
+                        } = config.as_ref();
+                        let mut description = description.clone().unwrap_or_default();
+                        if let Some(depends) = depends.as_ref().filter(|d| !d.is_empty()) {
+                            description = format!("{description} (after {})", depends.join(", "));
+                        }
+                        description.trim().to_string()
+                    }
+                };
+                (name.as_str(), description)
+            })
+            .collect()
+    }
+
+    /// The services along with their descriptions, sorted by name.
+    fn service_descriptions(&self) -> BTreeMap<&str, String> {
+        self.services
+            .iter()
+            .flatten()
+            .map(|(name, service)| {
+                let mut description = service.description.clone().unwrap_or_default();
+                if let Some(profiles) = service.profiles.as_ref().filter(|p| !p.is_empty()) {
+                    description = format!("{description} [{}]", profiles.join(", "));
+                }
+                if service.autostart == Some(false) {
+                    description = format!("{description} (not started by default)");
+                }
+                (name.as_str(), description.trim().to_string())
+            })
+            .collect()
+    }
+
+    /// The profiles and groups along with the services in them, sorted by name.
+    fn profile_descriptions(&self) -> BTreeMap<&str, String> {
+        let mut profiles: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (name, service) in self.services.iter().flatten() {
+            for profile in service.profiles.iter().flatten() {
+                profiles.entry(profile).or_default().push(name);
+            }
+        }
+        for (group, members) in self.groups.iter().flatten() {
+            profiles
+                .entry(group)
+                .or_default()
+                .extend(members.iter().map(String::as_str));
+        }
+
+        profiles
+            .into_iter()
+            .map(|(profile, members)| (profile, members.into_iter().unique().join(", ")))
+            .collect()
+    }
+}
+
+/// Implements `devit list`, which prints what the project offers.
+pub fn print_list(project: &ProjectDesc) {
+    let sections = [
+        ("Scripts", project.script_descriptions()),
+        ("Services", project.service_descriptions()),
+        ("Profiles", project.profile_descriptions()),
+    ];
+    let width = sections
+        .iter()
+        .flat_map(|(_, items)| items.keys())
+        .map(|name| name.len())
+        .max()
+        .unwrap_or_default();
+
+    for (title, items) in sections {
+        if items.is_empty() {
+            continue;
+        }
+
+        println!("{title}:");
+        for (name, description) in items {
+            let line = format!("  {name:width$}  {description}");
+            println!("{}", line.trim_end());
+        }
+    }
+}
+
+/// Reads the project for shell completion. Errors are ignored as there's no way to report them.
+fn completion_project() -> Option<ProjectDesc> {
+    let toml_file = match std::env::var_os("DEVIT_PROJECT") {
+        Some(path) => PathBuf::from(path),
+        None => crate::find_project_file().ok()?,
+    };
+
+    let mut project: ProjectDesc =
+        toml::from_str(&std::fs::read_to_string(toml_file).ok()?).ok()?;
+    project.apply_recipes().ok()?;
+    Some(project)
+}
+
+fn candidates(descriptions: BTreeMap<&str, String>) -> Vec<CompletionCandidate> {
+    descriptions
+        .into_iter()
+        .map(|(name, description)| {
+            CompletionCandidate::new(name)
+                .help(Some(description).filter(|d| !d.is_empty()).map(Into::into))
+        })
+        .collect()
+}
+
+pub fn script_candidates() -> Vec<CompletionCandidate> {
+    completion_project()
+        .map(|project| candidates(project.script_descriptions()))
+        .unwrap_or_default()
+}
+
+pub fn service_candidates() -> Vec<CompletionCandidate> {
+    completion_project()
+        .map(|project| candidates(project.service_descriptions()))
+        .unwrap_or_default()
+}
+
+pub fn profile_candidates() -> Vec<CompletionCandidate> {
+    completion_project()
+        .map(|project| candidates(project.profile_descriptions()))
+        .unwrap_or_default()
+}
@@ -1,30 +1,51 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use crate::model::{ProjectDesc, ProjectEnvironment};
+use crate::check::Diagnostic;
+use crate::control::Request;
+use crate::info::InfoFormat;
+use crate::model::{ProjectDesc, ProjectEnvironment, ScriptParams};
 use anyhow::{bail, Context};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{ArgValueCandidates, CompleteEnv};
 
+mod check;
+mod control;
 mod direnv;
+mod dotenv;
+mod info;
 mod init;
+mod list;
 mod model;
+mod ports;
+mod recipe;
 mod run;
+mod schema;
+mod secret;
 mod ser;
 mod service;
 mod shell;
+mod supervisor;
+mod tui;
 mod utils;
+mod watch;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(
+    after_help = "Completion of commands, scripts and services can be enabled by adding\n`source <(COMPLETE=bash devit)` to ~/.bashrc, or `COMPLETE=zsh` and `COMPLETE=fish` for other shells"
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    #[clap(short, default_value = "devit.toml")]
-    /// Use this TOML
-    path_to_toml: PathBuf,
+    #[clap(short, env = "DEVIT_PROJECT")]
+    /// Use this TOML. Defaults to the nearest devit.toml in the current or parent directories
+    path_to_toml: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -41,12 +62,76 @@ enum Commands {
 
     /// Bring up services
     Up {
-        /// The services to bring up. Default to all services if empty.
-        service_names: Option<Vec<String>>,
+        /// The services to bring up, along with the services they depend on. Default to all
+        /// services started automatically if neither services nor profiles are given.
+        #[clap(add = ArgValueCandidates::new(list::service_candidates))]
+        service_names: Vec<String>,
+
+        /// Bring up the services of a profile or group. Can be given several times.
+        #[clap(short, long = "profile", value_name = "PROFILE")]
+        #[clap(add = ArgValueCandidates::new(list::profile_candidates))]
+        profiles: Vec<String>,
+
+        /// Show the services in a dashboard, where they can be started, stopped or restarted
+        /// one by one and their logs searched
+        #[clap(long)]
+        tui: bool,
+    },
+
+    /// Start services under the running `devit up`, with the services they depend on
+    Start {
+        #[clap(required = true, add = ArgValueCandidates::new(list::service_candidates))]
+        service_names: Vec<String>,
+    },
+
+    /// Stop services of the running `devit up`, leaving the others running
+    Stop {
+        #[clap(required = true, add = ArgValueCandidates::new(list::service_candidates))]
+        service_names: Vec<String>,
+    },
+
+    /// Restart services of the running `devit up`
+    Restart {
+        #[clap(required = true, add = ArgValueCandidates::new(list::service_candidates))]
+        service_names: Vec<String>,
+    },
+
+    /// Show the services of the running `devit up`
+    Status {
+        /// Print the status as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Run a particular script, or list the scripts if none is given
+    Run {
+        /// The script to run, or several scripts with `-p`
+        #[clap(add = ArgValueCandidates::new(list::script_candidates))]
+        script_names: Vec<String>,
+
+        /// Run the scripts at the same time, with their output prefixed by the script name
+        #[clap(short, long)]
+        parallel: bool,
+
+        /// Stop the other scripts as soon as one fails
+        #[clap(long, requires = "parallel")]
+        fail_fast: bool,
+
+        /// Rerun the script whenever one of its inputs changes
+        #[clap(short, long, conflicts_with = "parallel")]
+        watch: bool,
+
+        /// Set a parameter declared by the script
+        #[clap(short, long = "set", value_name = "NAME=VALUE")]
+        set: Vec<String>,
+
+        /// Arguments passed to the script, available as "$@"
+        #[clap(last = true)]
+        args: Vec<String>,
     },
 
-    /// Run a particular script
-    Run { script_name: String },
+    /// List the scripts and services of the project
+    List,
 
     /// Install the necessary dependencies
     Install,
@@ -54,77 +139,324 @@ enum Commands {
     /// Print commands for direnv to set up the environment
     Direnv,
 
-    /// Print out the project information in json format
-    Info,
+    /// Print out the project information
+    Info {
+        /// The output format
+        #[clap(long, value_enum, default_value_t = InfoFormat::Json)]
+        format: InfoFormat,
+
+        /// Only print this field, e.g. `services.db.script`
+        #[clap(long)]
+        field: Option<String>,
+    },
+
+    /// Validate the project file and report any problems
+    Check,
+
+    /// Print the JSON schema of the project file, for editor completion and validation
+    Schema,
+}
+
+const PROJECT_FILE_NAME: &str = "devit.toml";
+
+/// Where devit keeps the state of a project, next to the project file.
+const STATE_DIR: &str = ".devit-state";
+
+/// Walks up from the current directory looking for the nearest project file. The search stops
+/// at a git root or when crossing into another filesystem.
+fn find_project_file() -> anyhow::Result<PathBuf> {
+    let current_dir = std::env::current_dir().context("getting current dir")?;
+    let device = current_dir.metadata().map(|m| m.dev()).ok();
+
+    let mut dir = current_dir.as_path();
+    loop {
+        let candidate = dir.join(PROJECT_FILE_NAME);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+
+        if dir.join(".git").exists() {
+            break;
+        }
+
+        match dir.parent() {
+            Some(parent) if parent.metadata().map(|m| m.dev()).ok() == device => dir = parent,
+            _ => break,
+        }
+    }
+
+    bail!(
+        "Unable to find {PROJECT_FILE_NAME} in {} or any of its parent directories",
+        current_dir.display()
+    )
 }
 
-async fn read_project(toml_file: impl AsRef<Path>) -> anyhow::Result<ProjectEnvironment> {
-    let toml_file = if toml_file.as_ref().is_relative() {
-        std::env::current_dir()
-            .context("getting current dir")?
-            .join(toml_file)
-    } else {
-        toml_file.as_ref().to_path_buf()
+fn resolve_project_file(path_to_toml: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let current_dir = std::env::current_dir().context("getting current dir")?;
+    let toml_file = match path_to_toml {
+        Some(path) => current_dir.join(path),
+        None => find_project_file()?,
     };
 
-    let mut file = File::open(&toml_file).context("Opening project file")?;
+    if toml_file.parent() != Some(current_dir.as_path()) {
+        eprintln!("Using project file {}", toml_file.display());
+    }
+
+    Ok(toml_file)
+}
+
+fn read_project_file(toml_file: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(toml_file).context("Opening project file")?;
     let mut file_contents = Default::default();
     file.read_to_string(&mut file_contents)
         .context("Reading file contents")?;
-    let project: ProjectDesc = toml::from_str(&file_contents).context("Parsing toml file")?;
+    Ok(file_contents)
+}
+
+/// Reads the project file without setting anything up, reporting any problems found in it.
+fn read_project_desc(path_to_toml: Option<&Path>) -> anyhow::Result<(PathBuf, ProjectDesc)> {
+    let toml_file = resolve_project_file(path_to_toml)?;
+    let file_contents = read_project_file(&toml_file)?;
+
+    let diagnostics = check::check_project(&file_contents);
+    check::print_diagnostics(&toml_file, &file_contents, &diagnostics);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        bail!("{} contains errors", toml_file.display());
+    }
+
+    let project = parse_project(&file_contents)?;
+    Ok((toml_file, project))
+}
+
+fn parse_project(file_contents: &str) -> anyhow::Result<ProjectDesc> {
+    let mut project: ProjectDesc = toml::from_str(file_contents).context("Parsing toml file")?;
+    project.apply_recipes()?;
+    Ok(project)
+}
+
+/// Sends a request to the `devit up` running for the project.
+async fn control_services(
+    path_to_toml: Option<&Path>,
+    request: Request,
+) -> anyhow::Result<control::Response> {
+    let toml_file = resolve_project_file(path_to_toml)?;
+    let project_dir = toml_file.parent().context("Getting parent")?;
+    control::send_request(&project_dir.join(STATE_DIR), &request).await
+}
 
+/// Reads the project. Secrets are only looked up with `lookup_secrets`, for commands that run
+/// something, so that the others work without them.
+async fn read_project(
+    path_to_toml: Option<&Path>,
+    lookup_secrets: bool,
+) -> anyhow::Result<ProjectEnvironment> {
+    read_project_with_params(path_to_toml, ScriptParams::default(), lookup_secrets).await
+}
+
+/// Reads the project, overriding the defaults of script parameters with `params`.
+async fn read_project_with_params(
+    path_to_toml: Option<&Path>,
+    params: ScriptParams<'_>,
+    lookup_secrets: bool,
+) -> anyhow::Result<ProjectEnvironment> {
+    let (toml_file, project) = read_project_desc(path_to_toml)?;
+    setup_project(toml_file, project, params, lookup_secrets).await
+}
+
+/// Reads the project file again for a running `devit up`. Warnings are left to `devit check`,
+/// and the first error fails the reload.
+async fn reload_project(toml_file: &Path) -> anyhow::Result<ProjectEnvironment> {
+    let file_contents = read_project_file(toml_file)?;
+    if let Some(error) = check::check_project(&file_contents)
+        .into_iter()
+        .find(Diagnostic::is_error)
+    {
+        bail!("{} contains errors: {}", toml_file.display(), error.message);
+    }
+
+    let project = parse_project(&file_contents)?;
+    setup_project(
+        toml_file.to_path_buf(),
+        project,
+        ScriptParams::default(),
+        true,
+    )
+    .await
+}
+
+async fn setup_project(
+    toml_file: PathBuf,
+    project: ProjectDesc,
+    params: ScriptParams<'_>,
+    lookup_secrets: bool,
+) -> anyhow::Result<ProjectEnvironment> {
     let project_dir = toml_file.parent().context("Getting parent")?;
 
-    project
+    let mut environment = project
         .to_environment(
             project_dir.to_str().context("path to dir")?,
-            project_dir
-                .join(".devit-state")
-                .to_str()
-                .unwrap_or_default(),
+            project_dir.join(STATE_DIR).to_str().unwrap_or_default(),
+            &params,
+            lookup_secrets,
         )
         .await
-        .context("environment")
+        .context("environment")?;
+
+    environment.source_files.insert(0, toml_file);
+    Ok(environment)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    CompleteEnv::with_factory(Cli::command).complete();
+
     let Cli {
         command,
         path_to_toml,
     } = Cli::parse();
 
     match command {
-        Commands::Init => init::init_project(path_to_toml),
+        Commands::Init => {
+            init::init_project(path_to_toml.unwrap_or_else(|| PROJECT_FILE_NAME.into()))
+        }
         Commands::Shell { args } => {
-            read_project(&path_to_toml)
+            read_project(path_to_toml.as_deref(), true)
                 .await
                 .context("reading project file")?
                 .run_shell(args.map(|args| args.join(" ")))
                 .await
         }
 
-        Commands::Up { service_names } => {
-            let info = read_project(&path_to_toml)
+        Commands::Up {
+            service_names,
+            profiles,
+            tui,
+        } => {
+            let mut info = read_project(path_to_toml.as_deref(), true)
                 .await
                 .context("reading project file")?;
+            let repicked = info.check_ports(&info.select_services(&service_names, &profiles)?)?;
+            if !repicked.is_empty() {
+                // The environment still refers to the ports that were taken
+                info = read_project(path_to_toml.as_deref(), true)
+                    .await
+                    .context("reading project file")?;
+            }
+
             if !info.services.is_empty() {
-                info.run_services(service_names).await
+                info.run_services(&service_names, &profiles, tui).await
             } else {
                 Ok(())
             }
         }
 
-        Commands::Run { script_name } => {
-            read_project(&path_to_toml)
-                .await
-                .context("reading project file")?
-                .run_script(&script_name)
-                .await
+        Commands::Start { service_names } => {
+            let request = Request::Start {
+                services: service_names.clone(),
+            };
+            control_services(path_to_toml.as_deref(), request).await?;
+            service_names
+                .iter()
+                .for_each(|name| println!("Starting {name}"));
+            Ok(())
+        }
+
+        Commands::Stop { service_names } => {
+            let request = Request::Stop {
+                services: service_names.clone(),
+            };
+            control_services(path_to_toml.as_deref(), request).await?;
+            service_names
+                .iter()
+                .for_each(|name| println!("Stopping {name}"));
+            Ok(())
+        }
+
+        Commands::Restart { service_names } => {
+            let request = Request::Restart {
+                services: service_names.clone(),
+            };
+            control_services(path_to_toml.as_deref(), request).await?;
+            service_names
+                .iter()
+                .for_each(|name| println!("Restarting {name}"));
+            Ok(())
+        }
+
+        Commands::Status { json } => {
+            let response = control_services(path_to_toml.as_deref(), Request::Status).await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&response.services).context("encoding status")?
+                );
+            } else {
+                control::print_status(&response.services);
+            }
+            Ok(())
+        }
+
+        Commands::Run {
+            script_names,
+            parallel,
+            fail_fast,
+            watch,
+            set,
+            args,
+        } => {
+            if script_names.is_empty() {
+                if parallel || watch {
+                    bail!("No script given");
+                }
+                list::print_list(&read_project_desc(path_to_toml.as_deref())?.1);
+                return Ok(());
+            }
+            if !parallel && script_names.len() > 1 {
+                bail!("Use -p to run several scripts at once, or -- to pass arguments to a script");
+            }
+            if parallel && !args.is_empty() {
+                bail!("Arguments can't be passed to scripts running in parallel");
+            }
+
+            let params = set
+                .iter()
+                .map(|param| {
+                    param
+                        .split_once('=')
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .with_context(|| {
+                            format!("invalid parameter '{param}', expected NAME=VALUE")
+                        })
+                })
+                .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+            let project = read_project_with_params(
+                path_to_toml.as_deref(),
+                ScriptParams {
+                    scripts: &script_names,
+                    values: Some(&params),
+                },
+                true,
+            )
+            .await
+            .context("reading project file")?;
+
+            if parallel {
+                project.run_scripts(&script_names, &params, fail_fast).await
+            } else if watch {
+                project.watch_script(&script_names[0], &params, &args).await
+            } else {
+                project.run_script(&script_names[0], &params, &args).await
+            }
+        }
+
+        Commands::List => {
+            list::print_list(&read_project_desc(path_to_toml.as_deref())?.1);
+            Ok(())
         }
 
         Commands::Install => {
-            let status = read_project(&path_to_toml)
+            let status = read_project(path_to_toml.as_deref(), false)
                 .await
                 .context("reading project file")?
                 .run_command("sh", false)
@@ -143,17 +475,24 @@ async fn main() -> anyhow::Result<()> {
             Ok(())
         }
 
-        Commands::Info => serde_json::to_writer_pretty(
-            std::io::stdout(),
-            &read_project(&path_to_toml)
+        Commands::Info { format, field } => info::print_info(
+            &read_project(path_to_toml.as_deref(), false)
                 .await
                 .context("reading project file")?,
-        )
-        .context("writing json"),
+            format,
+            field.as_deref(),
+        ),
+
+        Commands::Check => {
+            let toml_file = resolve_project_file(path_to_toml.as_deref())?;
+            check::check_project_file(&toml_file, &read_project_file(&toml_file)?)
+        }
+
+        Commands::Schema => schema::print_schema(),
 
         Commands::Direnv => {
             direnv::print_direnv_commands(
-                &read_project(&path_to_toml)
+                &read_project(path_to_toml.as_deref(), true)
                     .await
                     .context("reading project file")?,
             )
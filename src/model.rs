@@ -1,90 +1,442 @@
 use anyhow::Context;
 use derive_more::{Deref, Display};
 use indexmap::IndexMap;
-use maplit::hashmap;
+use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::time::Duration;
 
+use crate::dotenv;
+use crate::ports;
+use crate::schema;
+use crate::secret::{Secret, SecretResolver};
 use crate::utils::brew_prefixes;
+use crate::watch::DEFAULT_DEBOUNCE;
 
-#[derive(Debug, Display, Deserialize, Serialize, Eq, PartialEq, Deref)]
+/// A string that can refer to `{project_dir}`, `{state_dir}`, `{pkgs.NAME.path}`,
+/// `{vars.NAME}` and `{ports.NAME}`
+#[derive(Debug, Clone, Display, Deserialize, Serialize, Eq, PartialEq, Deref, JsonSchema)]
 pub struct TemplatedString(String);
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A Homebrew package, either as a version or with an explicit formula name
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum VersionSpec {
     // postgresql = "12"
     // postgresql = "*"
     // postgresql = ""
+    /// The version of the formula named after the key, "*" or "" for the latest
+    #[schemars(example = "schema::example_version")]
     VersionOnly(String),
 
     // elasticsearch = { name = "elastic/tap/elasticsearch-full" }
     // elasticsearch = { name = "elastic/tap/elasticsearch-full", version = "*" }
     Full {
+        /// The full formula name, including its tap
+        #[schemars(example = "schema::example_formula")]
         name: String,
+        /// The version of the formula, "*" or "" for the latest
+        #[schemars(example = "schema::example_version")]
         version: Option<String>,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
-#[serde(rename_all = "camelCase")]
+/// The value of an environment variable
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Value(TemplatedString),
+    Secret(SecretRef),
+}
+
+/// A value looked up from the secrets file, the environment or the secrets command
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SecretRef {
+    /// The name of the secret
+    #[schemars(example = "schema::example_secret")]
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// A `.env` style file relative to the project, which should be ignored by git
+    #[schemars(example = "schema::example_secrets_file")]
+    pub file: Option<PathBuf>,
+    /// A command that prints the secret, with `{name}` replaced by the name of the secret
+    #[schemars(example = "schema::example_secrets_command")]
+    pub command: Option<String>,
+}
+
+/// How a path variable is combined with the value inherited from the environment
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    #[default]
+    Prepend,
+    Append,
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PathVarConfig {
+    /// Entries to add before the ones from dependencies
+    #[schemars(example = "schema::example_path_entries")]
+    pub entries: Option<Vec<TemplatedString>>,
+    /// Subdirectories of every dependency to add, replacing the defaults for known variables
+    #[schemars(example = "schema::example_path_subdirs")]
+    pub subdirs: Option<Vec<String>>,
+    /// Whether to prepend (default), append or replace the inherited value
+    #[schemars(example = "schema::example_path_mode")]
+    pub mode: Option<PathMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ShellConfig {
+    /// The paths to prepend to the PATH environment variable
+    #[schemars(example = "schema::example_user_paths")]
     pub user_paths: Option<Vec<TemplatedString>>,
+    /// The script to run on shell startup
+    #[schemars(example = "schema::example_hook")]
     pub hook: Option<TemplatedString>,
+    /// The interactive shell to use for `devit shell`, defaults to $SHELL. bash, zsh and fish
+    /// are supported
+    #[schemars(example = "schema::example_shell_program")]
+    pub program: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub struct ServiceConfig {
+/// A local port, either fixed or picked automatically
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum PortSpec {
+    // web = 3000
+    Fixed(u16),
+    // db = "auto"
+    Auto(AutoPort),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoPort {
+    Auto,
+}
+
+/// Whether a service keeps running or is a job that runs to completion
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceKind {
+    /// Runs until stopped. Exiting stops the other services
+    #[default]
+    Daemon,
+    /// Runs once and exits, e.g. to migrate a database. Services depending on it wait until it
+    /// completes, and its failure stops the other services
+    Oneshot,
+}
+
+/// A script, either as bash or with its interpreter and arguments
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum ScriptSpec {
+    // build = "cargo build"
+    #[schemars(example = "schema::example_script")]
+    Inline(TemplatedString),
+
+    // report = { script = "print('hello')", interpreter = "python3" }
+    Full(Box<ScriptConfig>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ScriptConfig {
+    /// The body of the script. Use `\{` for a literal brace
+    #[schemars(example = "schema::example_python_script")]
     pub script: TemplatedString,
-    pub env: Option<HashMap<String, TemplatedString>>,
+    /// The program that runs the script, which is passed as a file. Defaults to the shebang
+    /// line of the script, or bash
+    #[schemars(example = "schema::example_interpreter")]
+    pub interpreter: Option<String>,
+    /// Arguments passed to the script
+    #[schemars(example = "schema::example_args")]
+    pub args: Option<Vec<TemplatedString>>,
+    /// The directory to run the script in, relative to the project
+    #[schemars(example = "schema::example_cwd")]
+    pub cwd: Option<TemplatedString>,
+    /// Named parameters and their defaults, available as `{params.NAME}` in this script, where
+    /// scripts run by bash get them quoted as one word. Override them with
+    /// `devit run NAME --set PARAM=VALUE`
+    #[schemars(example = "schema::example_params")]
+    pub params: Option<IndexMap<String, TemplatedString>>,
+    /// Scripts to run before this one, in order. Shared dependencies only run once
+    #[schemars(example = "schema::example_depends")]
+    pub depends: Option<Vec<String>>,
+    /// Globs of the files this script reads, relative to the project. When given, the script is
+    /// skipped if none of the files changed since its last successful run
+    #[schemars(example = "schema::example_inputs")]
+    pub inputs: Option<Vec<TemplatedString>>,
+    /// What the script does, shown by `devit list`
+    #[schemars(example = "schema::example_script_description")]
+    pub description: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+/// A service run by `devit up`. Its templates can also refer to `{service.name}`,
+/// `{service.dir}` and `{service.port}`, the port named after the service
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// A built-in recipe providing every other field, which can still be overridden one by one.
+    /// Its formula is added to the dependencies and its port to the ports
+    #[schemars(example = "schema::example_recipe")]
+    pub recipe: Option<String>,
+    /// The version of the recipe's formula
+    #[schemars(example = "schema::example_version")]
+    pub version: Option<String>,
+    /// The script that runs the service in the foreground. Required unless a recipe is used
+    #[schemars(example = "schema::example_service_script")]
+    pub script: Option<TemplatedString>,
+    /// Whether the service keeps running or runs to completion. Defaults to "daemon"
+    #[schemars(example = "schema::example_service_kind")]
+    pub kind: Option<ServiceKind>,
+    /// Runs in the service directory before the first start, e.g. to create a database. The
+    /// directory is removed again if this fails
+    #[schemars(example = "schema::example_service_init")]
+    pub init: Option<TemplatedString>,
+    /// Exits successfully once the service accepts connections
+    #[schemars(example = "schema::example_service_ready")]
+    pub ready: Option<TemplatedString>,
+    /// Stops the service, instead of sending it SIGTERM
+    #[schemars(example = "schema::example_service_stop")]
+    pub stop: Option<TemplatedString>,
+    /// Services to start first. This service waits until they are ready, or completed for
+    /// oneshot services, and they are brought up along with it
+    #[schemars(example = "schema::example_service_depends")]
+    pub depends: Option<Vec<String>>,
+    /// Profiles this service belongs to, so that `devit up --profile NAME` starts it
+    #[schemars(example = "schema::example_profiles")]
+    pub profiles: Option<Vec<String>>,
+    /// Whether `devit up` starts this service when no service or profile is given. Defaults
+    /// to true
+    #[schemars(example = "schema::example_autostart")]
+    pub autostart: Option<bool>,
+    /// Globs of files under the project directory. The service is restarted when one of them
+    /// changes
+    #[schemars(example = "schema::example_service_watch")]
+    pub watch: Option<Vec<TemplatedString>>,
+    /// Globs of files whose changes don't restart the service, even if they are watched
+    #[schemars(example = "schema::example_service_ignore")]
+    pub ignore: Option<Vec<TemplatedString>>,
+    /// How many milliseconds files have to stay unchanged before the service is restarted.
+    /// Defaults to 300
+    #[schemars(example = "schema::example_debounce")]
+    pub debounce: Option<u64>,
+    /// The program that runs the script. Defaults to the shebang line of the script, or bash
+    #[schemars(example = "schema::example_interpreter")]
+    pub interpreter: Option<String>,
+    /// Environment variables set for this service only
+    #[schemars(example = "schema::example_service_env")]
+    pub env: Option<HashMap<String, EnvValue>>,
+    /// Environment variables telling shells and scripts how to reach this service, such as a
+    /// connection URL. `env` and env files take precedence over them
+    #[schemars(example = "schema::example_service_exports")]
+    pub exports: Option<HashMap<String, EnvValue>>,
+    /// What the service provides, shown by `devit list`
+    #[schemars(example = "schema::example_service_description")]
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectDesc {
+    /// Shell related configuration
+    #[schemars(example = "schema::example_shell")]
     pub shell: Option<ShellConfig>,
+    /// The Homebrew packages this project depends on
+    #[schemars(example = "schema::example_dependencies")]
     pub dependencies: IndexMap<String, VersionSpec>,
-    pub env: Option<HashMap<String, TemplatedString>>,
+    /// Colon separated path variables such as PYTHONPATH, in addition to the built-in ones
+    #[schemars(example = "schema::example_paths")]
+    pub paths: Option<IndexMap<String, PathVarConfig>>,
+    /// Environment variables to set
+    #[schemars(example = "schema::example_env")]
+    pub env: Option<HashMap<String, EnvValue>>,
+    /// `.env` style files to load, relative to the project. Later files override earlier ones
+    /// and `env` overrides them all. Missing files are skipped
+    #[schemars(example = "schema::example_env_files")]
+    pub env_files: Option<Vec<PathBuf>>,
+    /// Inherited environment variables to remove, e.g. a global PYTHONHOME
+    #[schemars(example = "schema::example_unset")]
+    pub unset: Option<Vec<String>>,
+    /// Start from a minimal environment with only HOME, USER, LOGNAME, SHELL, TERM, LANG,
+    /// TMPDIR, a system PATH and the `passthrough` variables
+    #[schemars(example = "schema::example_isolate")]
+    pub isolate: Option<bool>,
+    /// Inherited variables to keep when `isolate` is set
+    #[schemars(example = "schema::example_passthrough")]
+    pub passthrough: Option<Vec<String>>,
+    /// Where the secrets referred to in `env` come from
+    #[schemars(example = "schema::example_secrets")]
+    pub secrets: Option<SecretsConfig>,
+    /// Services to run with `devit up`
+    #[schemars(example = "schema::example_services")]
     pub services: Option<HashMap<String, ServiceConfig>>,
-    pub scripts: Option<HashMap<String, TemplatedString>>,
+    /// Scripts that can be run with `devit run <name>`
+    #[schemars(example = "schema::example_scripts")]
+    pub scripts: Option<HashMap<String, ScriptSpec>>,
+    /// Variables that can be reused across the scripts as `{vars.NAME}`
+    #[schemars(example = "schema::example_vars")]
     pub vars: Option<HashMap<String, String>>,
+    /// Local ports used by the project as `{ports.NAME}`. `auto` picks a free port once and
+    /// keeps it, so that several checkouts can run side by side
+    #[schemars(example = "schema::example_ports")]
+    pub ports: Option<IndexMap<String, PortSpec>>,
+    /// Named sets of services, started with `devit up --profile NAME` along with the services
+    /// listing that profile
+    #[schemars(example = "schema::example_groups")]
+    pub groups: Option<IndexMap<String, Vec<String>>>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Script {
+    pub body: String,
+    pub interpreter: Option<String>,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub params: IndexMap<String, String>,
+    pub depends: Vec<String>,
+    pub inputs: Vec<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ServiceEnvironment {
-    pub script: String,
+    pub script: Script,
+    pub kind: ServiceKind,
+    pub init: Option<Script>,
+    pub ready: Option<Script>,
+    pub stop: Option<Script>,
+    pub depends: Vec<String>,
+    pub profiles: Vec<String>,
+    pub autostart: bool,
+    pub watch: Vec<String>,
+    pub ignore: Vec<String>,
+    pub debounce: Duration,
     pub environ: HashMap<String, String>,
+    pub secrets: HashMap<String, Secret>,
     pub working_directory: PathBuf,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PathVar {
+    pub entries: Vec<String>,
+    pub mode: PathMode,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ProjectEnvironment {
-    pub environ: HashMap<String, String>,
+    pub environ: IndexMap<String, PathVar>,
     pub user_environ: HashMap<String, String>,
-    pub scripts: HashMap<String, String>,
+    pub secrets: HashMap<String, Secret>,
+    pub scripts: HashMap<String, Script>,
     pub services: HashMap<String, ServiceEnvironment>,
     pub shell_hook: Option<String>,
+    pub shell_program: Option<String>,
     pub state_dir: PathBuf,
+    pub project_dir: PathBuf,
+    pub dependencies: IndexMap<String, DependencyInfo>,
+    pub vars: IndexMap<String, String>,
+    pub ports: IndexMap<String, u16>,
+    pub groups: IndexMap<String, Vec<String>>,
+    pub source_files: Vec<PathBuf>,
+    pub unset: Vec<String>,
+    pub isolate: bool,
+    pub passthrough: Vec<String>,
 }
 
-#[derive(Serialize, Clone)]
-struct DependencyInfo {
-    name: String,
-    path: PathBuf,
+#[derive(Debug, Serialize, Clone)]
+pub struct DependencyInfo {
+    pub name: String,
+    pub path: PathBuf,
 }
 
+/// The path variables populated from every dependency, with the subdirectories they use
+const DEFAULT_PATH_VARS: &[(&str, &[&str])] = &[
+    ("PATH", &["bin", "sbin"]),
+    ("LIBRARY_PATH", &["lib"]),
+    ("C_INCLUDE_PATH", &["include"]),
+    ("CPLUS_INCLUDE_PATH", &["include"]),
+    ("PKG_CONFIG_PATH", &["lib/pkgconfig", "share/pkgconfig"]),
+    ("MANPATH", &["share/man"]),
+];
+
 #[derive(Serialize)]
 struct RenderContext {
     state_dir: PathBuf,
     project_dir: PathBuf,
     pkgs: IndexMap<String, DependencyInfo>,
     vars: IndexMap<String, String>,
+    ports: IndexMap<String, u16>,
+}
+
+impl RenderContext {
+    fn for_service<'a>(&'a self, name: &'a str) -> ServiceContext<'a> {
+        ServiceContext {
+            base: self,
+            service: ServiceInfo {
+                name,
+                dir: self.state_dir.join(name),
+                port: self.ports.get(name).copied(),
+            },
+        }
+    }
+}
+
+/// The context of a service template, which can also refer to the service itself.
+#[derive(Serialize)]
+struct ServiceContext<'a> {
+    #[serde(flatten)]
+    base: &'a RenderContext,
+    service: ServiceInfo<'a>,
+}
+
+#[derive(Serialize)]
+struct ServiceInfo<'a> {
+    name: &'a str,
+    dir: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+}
+
+/// Parameters set on the command line for the scripts being run.
+#[derive(Default, Clone, Copy)]
+pub struct ScriptParams<'a> {
+    pub scripts: &'a [String],
+    pub values: Option<&'a HashMap<String, String>>,
+}
+
+/// The context of a script template, which can also refer to the script's own parameters.
+#[derive(Serialize)]
+struct ScriptContext<'a> {
+    #[serde(flatten)]
+    base: &'a RenderContext,
+    params: &'a IndexMap<String, String>,
 }
 
 impl VersionSpec {
+    /// The version asked for, "*" or "" for the latest.
+    pub fn version(&self) -> &str {
+        match self {
+            VersionSpec::VersionOnly(version) => version,
+            VersionSpec::Full { version, .. } => version.as_deref().unwrap_or_default(),
+        }
+    }
+
     pub fn to_brew_name<'a>(&'a self, key: &'a str) -> Cow<'a, str> {
         match self {
             VersionSpec::VersionOnly(s) if s.trim() == "*" || s.trim().is_empty() => {
@@ -102,11 +454,28 @@ impl VersionSpec {
     }
 }
 
+impl Script {
+    /// The explicit interpreter, or the one from the shebang line
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_deref().or_else(|| {
+            self.body
+                .strip_prefix("#!")
+                .and_then(|s| s.lines().next())
+                .map(str::trim)
+        })
+    }
+}
+
 impl ProjectDesc {
+    /// Sets up the environment of the project. `params` override the parameter defaults of the
+    /// scripts being run, but not of their dependencies. Secrets are only looked up with
+    /// `lookup_secrets`, for commands that run something.
     pub async fn to_environment(
         &self,
         project_dir: impl AsRef<Path>,
         state_dir: impl AsRef<Path>,
+        params: &ScriptParams<'_>,
+        lookup_secrets: bool,
     ) -> anyhow::Result<ProjectEnvironment> {
         let project_dir = project_dir.as_ref().to_path_buf();
         let state_dir = state_dir.as_ref().to_path_buf();
@@ -143,13 +512,13 @@ impl ProjectDesc {
             .values()
             .filter(|info| !Path::new(&info.path).exists())
             .map(|info| {
-                println!("{} doesn't exist", &info.name);
+                eprintln!("{} doesn't exist", &info.name);
                 info.name.as_str()
             })
             .collect::<Vec<_>>();
 
         if !to_install.is_empty() {
-            println!("Installing {}", to_install.join(", "));
+            eprintln!("Installing {}", to_install.join(", "));
             let output = Command::new("brew")
                 .arg("install")
                 .args(to_install.iter())
@@ -164,7 +533,7 @@ impl ProjectDesc {
         }
 
         let render_context = RenderContext {
-            project_dir,
+            project_dir: project_dir.clone(),
             state_dir: state_dir.clone(),
             vars: self
                 .vars
@@ -174,57 +543,120 @@ impl ProjectDesc {
                 .map(|(k, v)| (k.clone(), v.clone()))
                 .collect(),
             pkgs: pkgs.clone(),
+            ports: ports::allocate_ports(self.ports.as_ref(), &state_dir)
+                .context("allocating ports")?,
         };
 
-        let path = self
-            .shell
-            .as_ref()
-            .and_then(|s| s.user_paths.as_ref())
+        let path_configs = self.paths.as_ref();
+        let names = DEFAULT_PATH_VARS
             .iter()
-            .flat_map(|v| v.iter())
-            .map(|t| render_template(t, &render_context).expect("To render"))
-            .chain(pkgs.iter().flat_map(|(_, info)| {
-                ["bin", "sbin"].iter().map(|sub| {
-                    info.path
-                        .join(sub)
-                        .to_str()
-                        .expect("path to string")
-                        .to_string()
-                })
-            }))
-            .collect::<Vec<_>>()
-            .join(":");
+            .map(|(name, _)| *name)
+            .chain(
+                path_configs
+                    .iter()
+                    .flat_map(|c| c.keys().map(String::as_str)),
+            )
+            .unique();
 
-        let lib_path = pkgs
-            .iter()
-            .filter_map(|(_, p)| p.path.join("lib").to_str().map(|s| s.to_string()))
-            .collect::<Vec<_>>()
-            .join(":");
+        let mut environ = IndexMap::new();
+        for name in names {
+            let config = path_configs.and_then(|c| c.get(name));
+            let subdirs = match config.and_then(|c| c.subdirs.as_ref()) {
+                Some(subdirs) => subdirs.iter().map(String::as_str).collect(),
+                None => DEFAULT_PATH_VARS
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, subdirs)| subdirs.to_vec())
+                    .unwrap_or_default(),
+            };
 
-        let include_path = pkgs
-            .iter()
-            .filter_map(|(_, p)| p.path.join("include").to_str().map(|s| s.to_string()))
-            .collect::<Vec<_>>()
-            .join(":");
-
-        let environ = hashmap! {
-            String::from("PATH") => path,
-            String::from("LIBRARY_PATH") => lib_path,
-            String::from("C_INCLUDE_PATH") => include_path.clone(),
-            String::from("CPLUS_INCLUDE_PATH") => include_path,
-        };
+            let user_paths = if name == "PATH" {
+                self.shell.as_ref().and_then(|s| s.user_paths.as_ref())
+            } else {
+                None
+            };
 
-        let user_environ = self
-            .env
-            .iter()
-            .flat_map(|m| m.iter())
-            .map(|(n, v)| {
-                (
-                    n.clone(),
-                    render_template(v, &render_context).expect("to render environment"),
-                )
-            })
-            .collect();
+            let configured: Vec<_> = user_paths
+                .into_iter()
+                .chain(config.and_then(|c| c.entries.as_ref()))
+                .flatten()
+                .map(|t| render_template(t, &render_context))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("entries of {name}"))?;
+            let entries = configured
+                .into_iter()
+                .chain(pkgs.values().flat_map(|info| {
+                    subdirs
+                        .iter()
+                        .map(|sub| info.path.join(sub))
+                        .filter(|dir| dir.is_dir())
+                        .map(|dir| dir.to_str().expect("path to string").to_string())
+                }))
+                .unique()
+                .collect();
+
+            environ.insert(
+                name.to_string(),
+                PathVar {
+                    entries,
+                    mode: config.and_then(|c| c.mode).unwrap_or_default(),
+                },
+            );
+        }
+
+        let secret_resolver =
+            SecretResolver::new(self.secrets.as_ref(), &project_dir, lookup_secrets)
+                .context("reading secrets")?;
+
+        // Services export how to reach them first, so that env files and env can override it
+        let mut user_environ = HashMap::new();
+        let mut secrets = HashMap::new();
+        for (name, service) in self.services.iter().flatten().sorted_by_key(|(k, _)| *k) {
+            let context = render_context.for_service(name);
+            let (exports, export_secrets) =
+                render_env(service.exports.as_ref(), &context, &secret_resolver)
+                    .await
+                    .with_context(|| format!("exports of service {name}"))?;
+            merge_env(&mut user_environ, &mut secrets, exports, export_secrets);
+        }
+
+        let mut source_files = Vec::new();
+        for file in self.env_files.iter().flatten() {
+            let path = project_dir.join(file);
+            if !path.is_file() {
+                continue;
+            }
+
+            let vars = dotenv::parse(
+                &read_to_string(&path).with_context(|| format!("reading {}", path.display()))?,
+                |name| {
+                    user_environ
+                        .get(name)
+                        .cloned()
+                        .or_else(|| std::env::var(name).ok())
+                },
+            )
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+            merge_env(
+                &mut user_environ,
+                &mut secrets,
+                vars.into_iter().collect(),
+                HashMap::new(),
+            );
+            source_files.push(path);
+        }
+        source_files.extend(
+            self.secrets
+                .as_ref()
+                .and_then(|s| s.file.as_ref())
+                .map(|file| project_dir.join(file))
+                .filter(|path| path.is_file()),
+        );
+
+        let (env, env_secrets) =
+            render_env(self.env.as_ref(), &render_context, &secret_resolver).await?;
+        merge_env(&mut user_environ, &mut secrets, env, env_secrets);
 
         let scripts = self
             .scripts
@@ -232,56 +664,256 @@ impl ProjectDesc {
             .iter()
             .flat_map(|v| v.iter())
             .map(|(name, script)| {
-                (
-                    name.to_string(),
-                    render_template(script, &render_context).expect("to render"),
-                )
-            })
-            .collect();
-
-        let services = self
-            .services
-            .as_ref()
-            .iter()
-            .flat_map(|v| v.iter())
-            .map(|(name, ServiceConfig { script, env })| {
-                (
-                    name.clone(),
-                    ServiceEnvironment {
-                        environ: env
+                let render = |t: &TemplatedString| {
+                    render_template(t, &render_context).with_context(|| format!("script {name}"))
+                };
+                let script = match script {
+                    ScriptSpec::Inline(body) => Script {
+                        body: render(body)?,
+                        ..Default::default()
+                    },
+                    ScriptSpec::Full(config) => {
+                        let ScriptConfig {
+                            script,
+                            interpreter,
+                            args,
+                            cwd,
+                            params: defaults,
+                            depends,
+                            inputs,
+                            description,
+                        } = config.as_ref();
+                        let overrides = params.values.filter(|_| params.scripts.contains(name));
+                        let params: IndexMap<_, _> = defaults
                             .iter()
-                            .flat_map(|v| v.iter())
-                            .map(|(name, tpl)| {
-                                (
-                                    name.clone(),
-                                    render_template(tpl, &render_context).expect("to render env"),
-                                )
+                            .flatten()
+                            .map(|(k, v)| {
+                                let value = match overrides.and_then(|o| o.get(k)) {
+                                    Some(value) => value.clone(),
+                                    None => render(v)?,
+                                };
+                                Ok((k.clone(), value))
                             })
-                            .collect(),
-                        script: render_template(script, &render_context).expect("to render script"),
-                        working_directory: state_dir.join(&name),
-                    },
-                )
+                            .collect::<anyhow::Result<_>>()?;
+                        let context = ScriptContext {
+                            base: &render_context,
+                            params: &params,
+                        };
+                        let render = |t: &TemplatedString| {
+                            render_template(t, &context).with_context(|| format!("script {name}"))
+                        };
+
+                        // Parameters are words of their own in scripts run by bash
+                        let body = if interpreter.is_none() && !script.as_str().starts_with("#!") {
+                            let quoted = params
+                                .iter()
+                                .map(|(k, v)| (k.clone(), shell_quote(v)))
+                                .collect();
+                            let context = ScriptContext {
+                                base: &render_context,
+                                params: &quoted,
+                            };
+                            render_template(script, &context)
+                                .with_context(|| format!("script {name}"))?
+                        } else {
+                            render(script)?
+                        };
+
+                        Script {
+                            body,
+                            interpreter: interpreter.clone(),
+                            args: args
+                                .iter()
+                                .flatten()
+                                .map(render)
+                                .collect::<anyhow::Result<_>>()?,
+                            cwd: match cwd {
+                                Some(cwd) => Some(project_dir.join(render(cwd)?)),
+                                None => None,
+                            },
+                            depends: depends.clone().unwrap_or_default(),
+                            description: description.clone(),
+                            inputs: inputs
+                                .iter()
+                                .flatten()
+                                .map(|input| {
+                                    Ok(project_dir
+                                        .join(render(input)?)
+                                        .to_string_lossy()
+                                        .to_string())
+                                })
+                                .collect::<anyhow::Result<_>>()?,
+                            params,
+                        }
+                    }
+                };
+                anyhow::Ok((name.to_string(), script))
             })
-            .collect();
+            .collect::<anyhow::Result<_>>()?;
+
+        let mut services = HashMap::new();
+        for (
+            name,
+            ServiceConfig {
+                script,
+                kind,
+                init,
+                ready,
+                stop,
+                depends,
+                profiles,
+                autostart,
+                watch,
+                ignore,
+                debounce,
+                interpreter,
+                env,
+                description,
+                ..
+            },
+        ) in self.services.iter().flatten()
+        {
+            let context = render_context.for_service(name);
+            let (environ, secrets) = render_env(env.as_ref(), &context, &secret_resolver)
+                .await
+                .with_context(|| format!("environment of service {name}"))?;
+
+            let script = script
+                .as_ref()
+                .with_context(|| format!("service {name} has no script"))?;
+            let globs = |globs: &Option<Vec<TemplatedString>>| {
+                globs
+                    .iter()
+                    .flatten()
+                    .map(|glob| {
+                        let glob = render_template(glob, &context)
+                            .with_context(|| format!("watch glob of service {name}"))?;
+                        Ok(project_dir.join(glob).to_string_lossy().to_string())
+                    })
+                    .collect::<anyhow::Result<_>>()
+            };
+            let command = |t: &TemplatedString| {
+                anyhow::Ok(Script {
+                    body: render_template(t, &context)
+                        .with_context(|| format!("command of service {name}"))?,
+                    ..Default::default()
+                })
+            };
+
+            services.insert(
+                name.clone(),
+                ServiceEnvironment {
+                    environ,
+                    secrets,
+                    script: Script {
+                        body: render_template(script, &context)
+                            .with_context(|| format!("script of service {name}"))?,
+                        interpreter: interpreter.clone(),
+                        description: description.clone(),
+                        ..Default::default()
+                    },
+                    kind: kind.unwrap_or_default(),
+                    init: init.as_ref().map(command).transpose()?,
+                    ready: ready.as_ref().map(command).transpose()?,
+                    stop: stop.as_ref().map(command).transpose()?,
+                    depends: depends.clone().unwrap_or_default(),
+                    profiles: profiles.clone().unwrap_or_default(),
+                    autostart: autostart.unwrap_or(true),
+                    watch: globs(watch)?,
+                    ignore: globs(ignore)?,
+                    debounce: debounce.map_or(DEFAULT_DEBOUNCE, Duration::from_millis),
+                    working_directory: context.service.dir.clone(),
+                },
+            );
+        }
 
         Ok(ProjectEnvironment {
             environ,
             user_environ,
+            secrets,
             scripts,
             services,
             shell_hook: self
                 .shell
                 .as_ref()
                 .and_then(|s| s.hook.as_ref())
-                .map(|t| render_template(t, &render_context).expect("to render hook")),
+                .map(|t| render_template(t, &render_context).context("shell hook"))
+                .transpose()?,
+            shell_program: self.shell.as_ref().and_then(|s| s.program.clone()),
             state_dir,
+            project_dir,
+            dependencies: pkgs,
+            vars: render_context.vars,
+            ports: render_context.ports,
+            groups: self.groups.clone().unwrap_or_default(),
+            source_files,
+            unset: self.unset.clone().unwrap_or_default(),
+            isolate: self.isolate.unwrap_or_default(),
+            passthrough: self.passthrough.clone().unwrap_or_default(),
         })
     }
 }
 
+async fn render_env(
+    env: Option<&HashMap<String, EnvValue>>,
+    context: &impl Serialize,
+    secret_resolver: &SecretResolver<'_>,
+) -> anyhow::Result<(HashMap<String, String>, HashMap<String, Secret>)> {
+    let mut values = HashMap::new();
+    let mut secrets = HashMap::new();
+
+    for (name, value) in env.into_iter().flatten() {
+        match value {
+            EnvValue::Value(tpl) => {
+                values.insert(
+                    name.clone(),
+                    render_template(tpl, context).with_context(|| format!("variable {name}"))?,
+                );
+            }
+            EnvValue::Secret(SecretRef { secret }) => {
+                secrets.insert(name.clone(), secret_resolver.resolve(secret).await?);
+            }
+        }
+    }
+
+    Ok((values, secrets))
+}
+
+/// Adds values and secrets to an environment, replacing earlier variables of either kind.
+fn merge_env(
+    values: &mut HashMap<String, String>,
+    secrets: &mut HashMap<String, Secret>,
+    new_values: HashMap<String, String>,
+    new_secrets: HashMap<String, Secret>,
+) {
+    for (name, value) in new_values {
+        secrets.remove(&name);
+        values.insert(name, value);
+    }
+
+    for (name, secret) in new_secrets {
+        values.remove(&name);
+        secrets.insert(name, secret);
+    }
+}
+
+/// Quotes a value for bash unless it is a plain word.
+fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=.,:/@%".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
 fn render_template(tpl: &TemplatedString, context: impl Serialize) -> anyhow::Result<String> {
     let mut template = tinytemplate::TinyTemplate::new();
+    // Values go into commands and the environment, not HTML
+    template.set_default_formatter(&tinytemplate::format_unescaped);
     template
         .add_template("default", tpl.as_str())
         .context("Adding template")?;
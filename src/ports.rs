@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use indexmap::IndexMap;
+use itertools::Itertools;
+
+use crate::model::{PortSpec, ProjectEnvironment};
+
+/// Where the ports picked for `auto` are kept, so they stay the same across runs.
+const PORTS_FILE: &str = "ports.json";
+
+/// Resolves the ports of the project. An `auto` port is picked among the free local ports the
+/// first time, and reused afterwards.
+pub fn allocate_ports(
+    specs: Option<&IndexMap<String, PortSpec>>,
+    state_dir: &Path,
+) -> anyhow::Result<IndexMap<String, u16>> {
+    let saved = read_saved_ports(state_dir)?;
+
+    let mut ports = IndexMap::new();
+    let mut auto = BTreeMap::new();
+    for (name, spec) in specs.into_iter().flatten() {
+        if let PortSpec::Fixed(port) = spec {
+            ports.insert(name.clone(), *port);
+        }
+    }
+
+    for (name, spec) in specs.into_iter().flatten() {
+        if !matches!(spec, PortSpec::Auto(_)) {
+            continue;
+        }
+
+        let port = match saved.get(name) {
+            Some(port) if !ports.values().any(|p| p == port) => *port,
+            _ => loop {
+                let port = free_port()?;
+                if !ports.values().any(|p| *p == port) {
+                    break port;
+                }
+            },
+        };
+        ports.insert(name.clone(), port);
+        auto.insert(name.clone(), port);
+    }
+
+    // Keep the name order of the project file
+    let ports = specs
+        .into_iter()
+        .flatten()
+        .map(|(name, _)| (name.clone(), ports[name]))
+        .collect();
+
+    if auto != saved {
+        save_ports(state_dir, &auto)?;
+    }
+
+    Ok(ports)
+}
+
+/// The ports picked for `auto` by earlier runs.
+fn read_saved_ports(state_dir: &Path) -> anyhow::Result<BTreeMap<String, u16>> {
+    let state_file = state_dir.join(PORTS_FILE);
+    match std::fs::read_to_string(&state_file) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("parsing {}", state_file.display())),
+        Err(_) => Ok(Default::default()),
+    }
+}
+
+fn save_ports(state_dir: &Path, ports: &BTreeMap<String, u16>) -> anyhow::Result<()> {
+    let state_file = state_dir.join(PORTS_FILE);
+    std::fs::create_dir_all(state_dir).context("creating state dir")?;
+    std::fs::write(
+        &state_file,
+        serde_json::to_string_pretty(ports).context("writing ports")?,
+    )
+    .with_context(|| format!("writing {}", state_file.display()))
+}
+
+fn free_port() -> anyhow::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .context("finding a free port")?
+        .port())
+}
+
+impl ProjectEnvironment {
+    /// Makes sure nothing is listening on the ports of the given services, the ones named after
+    /// them, before they start. Ports picked automatically that are now taken are picked again
+    /// and returned, in which case the project has to be read again to use them.
+    pub fn check_ports(&self, services: &[&str]) -> anyhow::Result<Vec<String>> {
+        let mut saved = read_saved_ports(&self.state_dir)?;
+        let mut repicked = Vec::new();
+        let mut taken = Vec::new();
+        for name in services.iter().unique() {
+            let Some(&port) = self.ports.get(*name) else {
+                continue;
+            };
+            if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+                continue;
+            }
+
+            match saved.get_mut(*name) {
+                Some(saved) => {
+                    *saved = loop {
+                        let port = free_port()?;
+                        if !self.ports.values().any(|p| *p == port) {
+                            break port;
+                        }
+                    };
+                    eprintln!("Port {name} ({port}) is in use, using {saved} instead");
+                    repicked.push(name.to_string());
+                }
+                None => taken.push(format!("{name} ({port})")),
+            }
+        }
+
+        if !taken.is_empty() {
+            bail!("Ports already in use: {}", taken.join(", "));
+        }
+
+        if !repicked.is_empty() {
+            save_ports(&self.state_dir, &saved)?;
+        }
+        Ok(repicked)
+    }
+}
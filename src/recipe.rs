@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+
+use crate::model::{AutoPort, EnvValue, PortSpec, ProjectDesc, ServiceConfig, VersionSpec};
+
+lazy_static! {
+    static ref RECIPES: HashMap<String, ServiceConfig> =
+        toml::from_str(include_str!("recipes.toml")).expect("built-in recipes to parse");
+}
+
+/// The names of the built-in recipes, sorted.
+pub fn recipe_names() -> Vec<&'static str> {
+    let mut names: Vec<_> = RECIPES.keys().map(String::as_str).collect();
+    names.sort();
+    names
+}
+
+impl ServiceConfig {
+    /// Fills in the fields that aren't set from a recipe. Environment variables are merged one
+    /// by one.
+    fn with_defaults(self, recipe: &ServiceConfig) -> ServiceConfig {
+        ServiceConfig {
+            recipe: self.recipe,
+            version: self.version,
+            script: self.script.or_else(|| recipe.script.clone()),
+            kind: self.kind.or(recipe.kind),
+            init: self.init.or_else(|| recipe.init.clone()),
+            ready: self.ready.or_else(|| recipe.ready.clone()),
+            stop: self.stop.or_else(|| recipe.stop.clone()),
+            depends: self.depends.or_else(|| recipe.depends.clone()),
+            profiles: self.profiles.or_else(|| recipe.profiles.clone()),
+            autostart: self.autostart.or(recipe.autostart),
+            watch: self.watch.or_else(|| recipe.watch.clone()),
+            ignore: self.ignore.or_else(|| recipe.ignore.clone()),
+            debounce: self.debounce.or(recipe.debounce),
+            interpreter: self.interpreter.or_else(|| recipe.interpreter.clone()),
+            env: merge_env(self.env, &recipe.env),
+            exports: merge_env(self.exports, &recipe.exports),
+            description: self.description.or_else(|| recipe.description.clone()),
+        }
+    }
+}
+
+/// Whether two versions of a formula are the same, "*" and "" both meaning the latest.
+fn same_version(a: &str, b: &str) -> bool {
+    let latest = |v: &str| matches!(v.trim(), "*" | "");
+    (latest(a) && latest(b)) || a.trim() == b.trim()
+}
+
+/// Merges environment variables, the service's own taking precedence over the recipe's.
+fn merge_env(
+    own: Option<HashMap<String, EnvValue>>,
+    recipe: &Option<HashMap<String, EnvValue>>,
+) -> Option<HashMap<String, EnvValue>> {
+    match own {
+        Some(own) => Some(recipe.clone().into_iter().flatten().chain(own).collect()),
+        None => recipe.clone(),
+    }
+}
+
+impl ProjectDesc {
+    /// Expands the services based on a recipe. The recipe's formula is added to the
+    /// dependencies and a port named after the service to the ports, unless already there.
+    pub fn apply_recipes(&mut self) -> anyhow::Result<()> {
+        for (name, service) in self.services.iter_mut().flatten() {
+            let Some(recipe_name) = service.recipe.clone() else {
+                if service.script.is_none() {
+                    bail!("service {name} needs either a script or a recipe");
+                }
+                continue;
+            };
+
+            let recipe = RECIPES.get(&recipe_name).with_context(|| {
+                format!(
+                    "service {name} uses unknown recipe '{recipe_name}', available recipes are {}",
+                    recipe_names().join(", ")
+                )
+            })?;
+
+            match (self.dependencies.get(&recipe_name), &service.version) {
+                (Some(spec), Some(version)) if !same_version(spec.version(), version) => bail!(
+                    "service {name} wants {recipe_name} {version}, but the dependencies have {}",
+                    spec.to_brew_name(&recipe_name)
+                ),
+                (Some(_), _) => {}
+                (None, version) => {
+                    self.dependencies.insert(
+                        recipe_name,
+                        VersionSpec::VersionOnly(version.clone().unwrap_or_default()),
+                    );
+                }
+            }
+            self.ports
+                .get_or_insert_with(Default::default)
+                .entry(name.clone())
+                .or_insert(PortSpec::Auto(AutoPort::Auto));
+
+            *service = std::mem::take(service).with_defaults(recipe);
+        }
+
+        Ok(())
+    }
+}
@@ -1,13 +1,452 @@
-use crate::model::ProjectEnvironment;
-use anyhow::Context;
+use crate::model::{ProjectEnvironment, Script};
+use crate::service::{in_process_group, terminate};
+use crate::watch::{next_change, watch_files, whole_dir, FileFilter, DEFAULT_DEBOUNCE};
+use anyhow::{bail, Context};
+use futures::stream::{FuturesUnordered, StreamExt};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{exit, ExitStatus, Stdio};
+use std::time::UNIX_EPOCH;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::io::BufReader;
+use tokio::process::Command;
+use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
+
+/// What a script's last successful run depended on. The script is skipped when this is unchanged.
+#[derive(Serialize)]
+struct Fingerprint<'a> {
+    body: &'a str,
+    interpreter: Option<&'a str>,
+    cwd: Option<&'a PathBuf>,
+    params: &'a IndexMap<String, String>,
+    args: Vec<&'a String>,
+    env: BTreeMap<&'a str, &'a str>,
+    paths: BTreeMap<&'a str, &'a [String]>,
+    /// Only the names, values must not end up in the state directory
+    secrets: BTreeSet<&'a str>,
+    /// Modification time in nanoseconds and size of every input file
+    files: BTreeMap<PathBuf, (u128, u64)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    Inherit,
+    /// Each line is prefixed with the script name, for scripts running side by side
+    Prefixed,
+}
 
 impl ProjectEnvironment {
-    pub async fn run_script(&self, name: &str) -> anyhow::Result<()> {
-        let script = self
-            .scripts
+    /// Runs a script with extra arguments, after its dependencies. `params` are the parameters
+    /// set on the command line, which the script must declare.
+    pub async fn run_script(
+        &self,
+        name: &str,
+        params: &HashMap<String, String>,
+        args: &[String],
+    ) -> anyhow::Result<()> {
+        self.check_params(&[name], params)?;
+
+        let status = self.run_with_dependencies(name, args, None, true).await?;
+        exit(exit_code(status));
+    }
+
+    /// Runs several scripts at once with their output prefixed by the script name. Their
+    /// dependencies run one at a time beforehand. With `fail_fast`, the first failure stops
+    /// every other script.
+    pub async fn run_scripts(
+        &self,
+        names: &[String],
+        params: &HashMap<String, String>,
+        fail_fast: bool,
+    ) -> anyhow::Result<()> {
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.check_params(&names, params)?;
+
+        let mut dependencies = Vec::new();
+        for name in &names {
+            for dependency in self.script_dependencies(name)? {
+                if !dependencies.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+            }
+        }
+
+        for dependency in &dependencies {
+            let status = self
+                .run_task(dependency, &[], Output::Inherit, None, true)
+                .await?;
+            if !status.success() {
+                eprintln!("Script '{dependency}' failed, not running the others");
+                exit(exit_code(status));
+            }
+        }
+
+        let cancellation = CancellationToken::new();
+        let mut runs = names
+            .iter()
+            .filter(|name| !dependencies.contains(name))
+            .map(|name| {
+                let cancellation = &cancellation;
+                async move {
+                    let status = self
+                        .run_task(name, &[], Output::Prefixed, Some(cancellation), true)
+                        .await;
+                    (name, status)
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut failures = Vec::new();
+        let mut interrupted = false;
+        loop {
+            select! {
+                _ = tokio::signal::ctrl_c() => {
+                    interrupted = true;
+                    cancellation.cancel();
+                }
+
+                run = runs.next() => {
+                    let Some((name, status)) = run else {
+                        break;
+                    };
+
+                    match status {
+                        Ok(status) if status.success() => continue,
+                        // Scripts stopped on purpose are not failures on their own
+                        Ok(_) if cancellation.is_cancelled() => continue,
+                        Ok(status) => {
+                            eprintln!("Script '{name}' failed with {status}");
+                            failures.push(exit_code(status));
+                        }
+                        Err(e) => {
+                            eprintln!("Script '{name}' failed: {e:?}");
+                            failures.push(1);
+                        }
+                    }
+
+                    if fail_fast {
+                        cancellation.cancel();
+                    }
+                }
+            }
+        }
+
+        if interrupted {
+            exit(130);
+        }
+        exit(failures.first().copied().unwrap_or_default());
+    }
+
+    /// Runs a script and restarts it whenever one of its inputs changes, stopping the previous
+    /// run first. Runs until interrupted.
+    pub async fn watch_script(
+        &self,
+        name: &str,
+        params: &HashMap<String, String>,
+        args: &[String],
+    ) -> anyhow::Result<()> {
+        self.check_params(&[name], params)?;
+
+        let script = self.script(name)?;
+        if script.inputs.is_empty() {
+            bail!("script '{name}' has no inputs to watch");
+        }
+
+        // Runs write their fingerprint to the state directory
+        let filter = FileFilter::new(&script.inputs, &whole_dir(&self.state_dir))?;
+        let (_watcher, mut changes) = watch_files(&self.project_dir)?;
+
+        loop {
+            let cancellation = CancellationToken::new();
+            let run = self.run_with_dependencies(name, args, Some(&cancellation), false);
+            tokio::pin!(run);
+
+            let mut finished = false;
+            loop {
+                select! {
+                    status = &mut run, if !finished => {
+                        finished = true;
+                        match status {
+                            Ok(status) => eprintln!("Script '{name}' exited with {status}, waiting for changes"),
+                            Err(e) => eprintln!("Script '{name}' failed: {e:?}"),
+                        }
+                    }
+
+                    _ = next_change(&mut changes, &filter, DEFAULT_DEBOUNCE) => {
+                        eprintln!("Files changed, restarting '{name}'");
+                        break;
+                    }
+
+                    _ = tokio::signal::ctrl_c() => {
+                        cancellation.cancel();
+                        if !finished {
+                            let _ = run.await;
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+
+            cancellation.cancel();
+            if !finished {
+                let _ = run.await;
+            }
+        }
+    }
+
+    fn script(&self, name: &str) -> anyhow::Result<&Script> {
+        self.scripts
             .get(name)
-            .with_context(|| format!("unable to find script named '{name}'"))?;
+            .with_context(|| format!("unable to find script named '{name}'"))
+    }
 
-        self.run_shell(Some(script)).await
+    /// Makes sure every parameter set on the command line is declared by one of the scripts.
+    fn check_params(&self, names: &[&str], params: &HashMap<String, String>) -> anyhow::Result<()> {
+        let scripts = names
+            .iter()
+            .map(|name| self.script(name))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for param in params.keys() {
+            if !scripts.iter().any(|s| s.params.contains_key(param)) {
+                bail!(
+                    "script {} has no parameter '{param}'",
+                    names.iter().map(|name| format!("'{name}'")).join(", ")
+                );
+            }
+        }
+
+        Ok(())
     }
+
+    /// Runs the dependencies of a script one by one, then the script itself. Stops at the first
+    /// dependency that fails and returns its status.
+    async fn run_with_dependencies(
+        &self,
+        name: &str,
+        args: &[String],
+        cancellation: Option<&CancellationToken>,
+        skip_unchanged: bool,
+    ) -> anyhow::Result<ExitStatus> {
+        for dependency in self.script_dependencies(name)? {
+            let status = self
+                .run_task(dependency, &[], Output::Inherit, cancellation, true)
+                .await?;
+            if !status.success() {
+                eprintln!("Script '{dependency}' failed, not running '{name}'");
+                return Ok(status);
+            }
+        }
+
+        self.run_task(name, args, Output::Inherit, cancellation, skip_unchanged)
+            .await
+    }
+
+    /// Lists the scripts to run before `name`, dependencies first and each only once.
+    pub fn script_dependencies<'a>(&'a self, name: &'a str) -> anyhow::Result<Vec<&'a str>> {
+        fn visit<'a>(
+            project: &'a ProjectEnvironment,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> anyhow::Result<()> {
+            if order.contains(&name) {
+                return Ok(());
+            }
+            if path.contains(&name) {
+                bail!(
+                    "scripts depend on each other: {} -> {name}",
+                    path.join(" -> ")
+                );
+            }
+
+            path.push(name);
+            for dependency in &project.script(name)?.depends {
+                visit(project, dependency, path, order)
+                    .with_context(|| format!("dependency of script '{name}'"))?;
+            }
+            path.pop();
+            order.push(name);
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        visit(self, name, &mut Vec::new(), &mut order)?;
+        order.pop();
+        Ok(order)
+    }
+
+    /// Runs a single script. With `skip_unchanged`, scripts whose inputs are unchanged since the
+    /// last successful run are skipped. A script that can be cancelled runs in its own process
+    /// group without stdin, so that it can be stopped along with everything it spawned.
+    async fn run_task(
+        &self,
+        name: &str,
+        args: &[String],
+        output: Output,
+        cancellation: Option<&CancellationToken>,
+        skip_unchanged: bool,
+    ) -> anyhow::Result<ExitStatus> {
+        let script = self.script(name)?;
+        let state_file = self.state_dir.join("scripts").join(format!("{name}.json"));
+
+        let fingerprint = if script.inputs.is_empty() {
+            None
+        } else {
+            let fingerprint = fingerprint(self, script, args)?;
+            if skip_unchanged
+                && std::fs::read_to_string(&state_file).ok().as_ref() == Some(&fingerprint)
+            {
+                eprintln!("Skipping script '{name}', its inputs are unchanged");
+                return Ok(ExitStatus::from_raw(0));
+            }
+            Some(fingerprint)
+        };
+
+        let (mut cmd, _script_file) = self.script_command(script)?;
+        cmd.args(args);
+        match output {
+            Output::Inherit => cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit()),
+            Output::Prefixed => cmd.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        };
+        match cancellation {
+            Some(_) => in_process_group(&mut cmd).stdin(Stdio::null()),
+            None => cmd.stdin(Stdio::inherit()),
+        };
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("spawning script '{name}'"))?;
+
+        let log_monitor = match output {
+            Output::Inherit => None,
+            Output::Prefixed => Some(spawn(Self::monitor_outputs(
+                name.to_string(),
+                BufReader::new(child.stdout.take().context("taking out stdout")?),
+                BufReader::new(child.stderr.take().context("taking out stderr")?),
+            ))),
+        };
+
+        let status = match cancellation {
+            Some(cancellation) => select! {
+                _ = cancellation.cancelled() => {
+                    terminate(name, &mut child, |message| println!("{message}")).await
+                }
+                status = child.wait() => status.with_context(|| format!("wait for script '{name}'")),
+            },
+            None => child
+                .wait()
+                .await
+                .with_context(|| format!("wait for script '{name}'")),
+        }?;
+
+        if let Some(log_monitor) = log_monitor {
+            let _ = log_monitor.await;
+        }
+
+        if let Some(fingerprint) = fingerprint.filter(|_| status.success()) {
+            std::fs::create_dir_all(state_file.parent().context("state file parent")?)
+                .context("creating script state dir")?;
+            std::fs::write(&state_file, fingerprint)
+                .with_context(|| format!("writing {}", state_file.display()))?;
+        }
+
+        Ok(status)
+    }
+
+    /// Creates the command that runs a script. Scripts with an interpreter are written to a
+    /// temp file, which has to be kept until the command finishes. Bash scripts run the shell
+    /// hook first.
+    pub fn script_command(&self, script: &Script) -> anyhow::Result<(Command, Option<TempPath>)> {
+        let (mut cmd, file) = match script.interpreter() {
+            None => {
+                let hook = self.shell_hook.as_deref().unwrap_or_default();
+                let mut cmd = self.run_command("bash", true);
+                cmd.arg("-c")
+                    .arg(format!("set -e\n {hook}\n {}", script.body))
+                    .arg("devit");
+                (cmd, None)
+            }
+
+            Some(interpreter) => {
+                let mut file = NamedTempFile::new().context("creating script file")?;
+                file.write_all(script.body.as_bytes())
+                    .context("writing script file")?;
+                let path = file.into_temp_path();
+
+                let mut words = interpreter.split_whitespace();
+                let mut cmd = self.run_command(words.next().context("empty interpreter")?, true);
+                cmd.args(words).arg(&path);
+                (cmd, Some(path))
+            }
+        };
+
+        cmd.args(&script.args);
+        if let Some(cwd) = &script.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        Ok((cmd, file))
+    }
+}
+
+/// The exit code of a finished script. Like in shells, a script killed by a signal exits with
+/// 128 plus the signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+fn fingerprint(
+    project: &ProjectEnvironment,
+    script: &Script,
+    args: &[String],
+) -> anyhow::Result<String> {
+    let mut files = BTreeMap::new();
+    for pattern in &script.inputs {
+        for path in glob::glob(pattern).with_context(|| format!("invalid glob '{pattern}'"))? {
+            let path = path.context("reading input files")?;
+            let metadata = path.metadata().context("reading input file")?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let modified = metadata
+                .modified()
+                .context("reading modification time")?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            files.insert(path, (modified, metadata.len()));
+        }
+    }
+
+    serde_json::to_string(&Fingerprint {
+        body: &script.body,
+        interpreter: script.interpreter(),
+        cwd: script.cwd.as_ref(),
+        params: &script.params,
+        args: script.args.iter().chain(args).collect(),
+        env: project
+            .user_environ
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect(),
+        paths: project
+            .environ
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.entries.as_slice()))
+            .collect(),
+        secrets: project.secrets.keys().map(String::as_str).collect(),
+        files,
+    })
+    .context("writing fingerprint")
 }
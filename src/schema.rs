@@ -0,0 +1,227 @@
+use anyhow::Context;
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+
+use crate::model::ProjectDesc;
+
+pub fn print_schema() -> anyhow::Result<()> {
+    // TOML has no null, so optional fields are simply left out
+    let schema = SchemaSettings::draft07()
+        .with(|s| s.option_add_null_type = false)
+        .into_generator()
+        .into_root_schema_for::<ProjectDesc>();
+    serde_json::to_writer_pretty(std::io::stdout(), &schema).context("writing schema")?;
+    println!();
+    Ok(())
+}
+
+pub fn example_version() -> &'static str {
+    "15"
+}
+
+pub fn example_formula() -> &'static str {
+    "elastic/tap/elasticsearch-full"
+}
+
+pub fn example_user_paths() -> Vec<&'static str> {
+    vec!["{project_dir}/node_modules/.bin"]
+}
+
+pub fn example_hook() -> &'static str {
+    "echo Welcome to the project"
+}
+
+pub fn example_shell_program() -> &'static str {
+    "zsh"
+}
+
+pub fn example_shell() -> Value {
+    json!({ "userPaths": example_user_paths(), "hook": example_hook() })
+}
+
+pub fn example_service_script() -> &'static str {
+    "{pkgs.postgresql.path}/bin/postgres -D {state_dir}/postgresql"
+}
+
+pub fn example_service_env() -> Value {
+    json!({ "PGPORT": "5432" })
+}
+
+pub fn example_dependencies() -> Value {
+    json!({
+        "postgresql": "15",
+        "elasticsearch": { "name": "elastic/tap/elasticsearch-full" },
+    })
+}
+
+pub fn example_paths() -> Value {
+    json!({
+        "PYTHONPATH": { "entries": example_path_entries() },
+        "CMAKE_PREFIX_PATH": { "subdirs": [""], "mode": "append" },
+    })
+}
+
+pub fn example_path_entries() -> Vec<&'static str> {
+    vec!["{project_dir}/src"]
+}
+
+pub fn example_path_subdirs() -> Vec<&'static str> {
+    vec!["lib/pkgconfig", "share/pkgconfig"]
+}
+
+pub fn example_path_mode() -> &'static str {
+    "append"
+}
+
+pub fn example_env() -> Value {
+    json!({ "RUST_LOG": "debug", "DB_PASSWORD": { "secret": "DB_PASSWORD" } })
+}
+
+pub fn example_env_files() -> Vec<&'static str> {
+    vec![".env", ".env.local"]
+}
+
+pub fn example_unset() -> Vec<&'static str> {
+    vec!["PYTHONHOME", "GOPATH"]
+}
+
+pub fn example_isolate() -> bool {
+    true
+}
+
+pub fn example_passthrough() -> Vec<&'static str> {
+    vec!["SSH_AUTH_SOCK"]
+}
+
+pub fn example_secret() -> &'static str {
+    "DB_PASSWORD"
+}
+
+pub fn example_secrets_file() -> &'static str {
+    ".env.secrets"
+}
+
+pub fn example_secrets_command() -> &'static str {
+    "pass show myproject/{name}"
+}
+
+pub fn example_secrets() -> Value {
+    json!({ "file": example_secrets_file() })
+}
+
+pub fn example_services() -> Value {
+    json!({
+        "postgresql": {
+            "script": example_service_script(),
+            "env": example_service_env(),
+        },
+        "cache": { "recipe": example_recipe() },
+    })
+}
+
+pub fn example_service_kind() -> &'static str {
+    "oneshot"
+}
+
+pub fn example_service_watch() -> Value {
+    json!(["src/**/*.rs", "Cargo.toml"])
+}
+
+pub fn example_service_ignore() -> Value {
+    json!(["src/**/generated/*.rs"])
+}
+
+pub fn example_service_depends() -> Value {
+    json!(["db"])
+}
+
+pub fn example_profiles() -> Value {
+    json!(["backend"])
+}
+
+pub fn example_groups() -> Value {
+    json!({ "backend": ["db", "cache"] })
+}
+
+pub fn example_recipe() -> &'static str {
+    "redis"
+}
+
+pub fn example_service_init() -> &'static str {
+    "{pkgs.postgresql.path}/bin/initdb -D data"
+}
+
+pub fn example_service_ready() -> &'static str {
+    "{pkgs.postgresql.path}/bin/pg_isready -p {service.port}"
+}
+
+pub fn example_service_stop() -> &'static str {
+    "{pkgs.postgresql.path}/bin/pg_ctl stop -D data"
+}
+
+pub fn example_autostart() -> bool {
+    false
+}
+
+pub fn example_debounce() -> u64 {
+    1000
+}
+
+pub fn example_scripts() -> Value {
+    json!({
+        "build": example_script(),
+        "report": { "script": example_python_script(), "interpreter": example_interpreter() },
+    })
+}
+
+pub fn example_script() -> &'static str {
+    "cargo build --release"
+}
+
+pub fn example_python_script() -> &'static str {
+    "import sys\nprint(sys.version)"
+}
+
+pub fn example_interpreter() -> &'static str {
+    "python3"
+}
+
+pub fn example_args() -> Vec<&'static str> {
+    vec!["--verbose"]
+}
+
+pub fn example_cwd() -> &'static str {
+    "frontend"
+}
+
+pub fn example_params() -> Value {
+    json!({ "profile": "dev" })
+}
+
+pub fn example_depends() -> Value {
+    json!(["build"])
+}
+
+pub fn example_script_description() -> &'static str {
+    "Build the release binary"
+}
+
+pub fn example_service_exports() -> Value {
+    json!({ "DATABASE_URL": "postgres://localhost:{ports.db}/app" })
+}
+
+pub fn example_service_description() -> &'static str {
+    "The development database"
+}
+
+pub fn example_inputs() -> Value {
+    json!(["src/**/*.rs", "Cargo.toml"])
+}
+
+pub fn example_ports() -> Value {
+    json!({ "db": "auto", "web": 3000 })
+}
+
+pub fn example_vars() -> Value {
+    json!({ "DB_NAME": "app" })
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Serialize, Serializer};
+use tokio::process::Command;
+
+use crate::dotenv;
+use crate::model::SecretsConfig;
+use crate::utils::gather_command_output;
+
+const REDACTED: &str = "<redacted>";
+
+/// A value that must never be printed. Both [Debug] and [Serialize] only show a placeholder.
+/// Secrets are only looked up for commands that run something, the others only know their
+/// names.
+#[derive(Clone, PartialEq)]
+pub struct Secret(Option<String>);
+
+impl Secret {
+    /// The value of the secret, unless it wasn't looked up.
+    pub fn value(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Looks up secrets from the secrets file, then the environment, then the secrets command.
+pub struct SecretResolver<'a> {
+    file: Option<(PathBuf, HashMap<String, String>)>,
+    command: Option<&'a str>,
+    project_dir: &'a Path,
+    lookup: bool,
+}
+
+impl<'a> SecretResolver<'a> {
+    /// Without `lookup`, secrets are left without a value and nothing is read or run.
+    pub fn new(
+        config: Option<&'a SecretsConfig>,
+        project_dir: &'a Path,
+        lookup: bool,
+    ) -> anyhow::Result<Self> {
+        let file = match config.and_then(|c| c.file.as_ref()).filter(|_| lookup) {
+            Some(file) => {
+                let path = project_dir.join(file);
+                let values = if path.is_file() {
+                    warn_if_not_ignored(&path, project_dir);
+                    dotenv::parse(
+                        &read_to_string(&path)
+                            .with_context(|| format!("reading {}", path.display()))?,
+                        |name| std::env::var(name).ok(),
+                    )
+                    .with_context(|| format!("parsing {}", path.display()))?
+                    .into_iter()
+                    .collect()
+                } else {
+                    Default::default()
+                };
+                Some((path, values))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            file,
+            command: config.and_then(|c| c.command.as_deref()),
+            project_dir,
+            lookup,
+        })
+    }
+
+    pub async fn resolve(&self, name: &str) -> anyhow::Result<Secret> {
+        if !self.lookup {
+            return Ok(Secret(None));
+        }
+
+        if let Some(value) = self.file.as_ref().and_then(|(_, values)| values.get(name)) {
+            return Ok(Secret(Some(value.clone())));
+        }
+
+        if let Ok(value) = std::env::var(name) {
+            return Ok(Secret(Some(value)));
+        }
+
+        if let Some(command) = self.command {
+            let output = gather_command_output(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(command.replace("{name}", name))
+                    .current_dir(self.project_dir),
+            )
+            .await
+            .with_context(|| format!("running secrets command for {name}"))?;
+
+            return Ok(Secret(Some(output.trim_end_matches('\n').to_string())));
+        }
+
+        match &self.file {
+            Some((path, _)) => bail!(
+                "secret {name} is neither in {} nor in the environment",
+                path.display()
+            ),
+            None => bail!("secret {name} is not in the environment"),
+        }
+    }
+}
+
+fn warn_if_not_ignored(path: &Path, project_dir: &Path) {
+    let status = std::process::Command::new("git")
+        .arg("check-ignore")
+        .arg("-q")
+        .arg(path)
+        .current_dir(project_dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+
+    // Exit code 1 means the file is in a git repository and isn't ignored
+    if matches!(status.map(|s| s.code()), Ok(Some(1))) {
+        eprintln!(
+            "Warning: secrets file {} is not ignored by git",
+            path.display()
+        );
+    }
+}
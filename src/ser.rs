@@ -1,59 +1,46 @@
-use derive_more::Deref;
-use serde::de::{MapAccess, Visitor};
+use serde::de::Visitor;
 use serde::{Deserialize, Deserializer};
-use std::fmt::Formatter;
-use std::marker::PhantomData;
 
-#[derive(Deref)]
-pub struct ConfigMap<T>(Vec<T>);
+/// Returns the field names a derived struct accepts, as seen by serde after renaming.
+pub fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
 
-struct ConfigMapVisitor<T>(PhantomData<T>);
+    #[derive(Debug, derive_more::Display)]
+    struct Captured;
 
-impl<T> ConfigMapVisitor<T> {
-    fn new() -> Self {
-        ConfigMapVisitor(PhantomData::default())
+    impl std::error::Error for Captured {}
+
+    impl serde::de::Error for Captured {
+        fn custom<M: std::fmt::Display>(_: M) -> Self {
+            Captured
+        }
     }
-}
 
-impl<'de, T> Visitor<'de> for ConfigMapVisitor<T>
-where
-    T: TryFrom<(String, toml::Value)>,
-    <T as TryFrom<(String, toml::Value)>>::Error: std::error::Error,
-{
-    type Value = Vec<T>;
+    impl<'de> Deserializer<'de> for FieldsDeserializer<'_> {
+        type Error = Captured;
 
-    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        write!(formatter, "valid map")
-    }
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Captured> {
+            Err(Captured)
+        }
 
-    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-    where
-        A: MapAccess<'de>,
-    {
-        let mut vec = Vec::with_capacity(map.size_hint().unwrap_or_default());
-
-        while let Some(entry) = map.next_entry::<String, toml::Value>()? {
-            vec.push(
-                T::try_from(entry)
-                    .map_err(|e| serde::de::Error::custom(format!("Error creating enum: {e:?}")))?,
-            );
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Captured> {
+            *self.0 = fields;
+            Err(Captured)
         }
 
-        Ok(vec)
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
     }
-}
 
-impl<'de, T> Deserialize<'de> for ConfigMap<T>
-where
-    T: TryFrom<(String, toml::Value)>,
-    <T as TryFrom<(String, toml::Value)>>::Error: std::error::Error,
-{
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        Ok(ConfigMap(
-            deserializer.deserialize_map(ConfigMapVisitor::new())?,
-        ))
-    }
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
 }
@@ -1,144 +1,319 @@
-use crate::model::ProjectEnvironment;
+use crate::model::{ProjectEnvironment, Script, ServiceKind};
+use crate::supervisor::{Selection, ServiceStatus, Supervisor};
+use crate::tui::run_dashboard;
 use anyhow::{bail, Context};
-use nix::libc::{kill, pid_t, SIGTERM};
-use std::os::unix::prelude::ExitStatusExt;
+use itertools::Itertools;
+use nix::libc::{killpg, pid_t, setpgid, SIGKILL, SIGTERM};
 use std::process::{ExitStatus, Stdio};
 use std::time::Duration;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
-use tokio::task::JoinSet;
-use tokio::time::timeout;
-use tokio::{select, spawn};
-use tokio_util::sync::CancellationToken;
+use tempfile::TempPath;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::process::{Child, Command};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{sleep, timeout};
+
+/// How long to wait between two readiness checks of a service.
+const READY_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 impl ProjectEnvironment {
-    pub async fn run_services(&self, only: Option<Vec<String>>) -> anyhow::Result<()> {
-        let cancel_token = CancellationToken::new();
-        let mut js = JoinSet::new();
+    /// Works out which services `devit up` starts: the services named and those in the given
+    /// profiles or groups, or all the services started automatically if none is given. The
+    /// services they depend on are included, dependencies first.
+    pub fn select_services(
+        &self,
+        names: &[String],
+        profiles: &[String],
+    ) -> anyhow::Result<Vec<&str>> {
+        let mut selected: Vec<&str> = Vec::new();
+        for name in names {
+            let Some((name, _)) = self.services.get_key_value(name) else {
+                bail!("Service {name} does not exist");
+            };
+            selected.push(name);
+        }
 
-        if let Some(only) = only {
-            if let Some(service) = only
+        for profile in profiles {
+            let group = self.groups.get(profile);
+            let members: Vec<_> = self
+                .services
                 .iter()
-                .filter(|service| !self.services.contains_key(service.as_str()))
-                .next()
-            {
-                bail!("Service {service} does not exist")
+                .filter(|(_, service)| service.profiles.contains(profile))
+                .map(|(name, _)| name.as_str())
+                .sorted()
+                .chain(group.into_iter().flatten().map(String::as_str))
+                .collect();
+            if members.is_empty() && group.is_none() {
+                bail!("Profile {profile} does not exist");
             }
+            selected.extend(members);
+        }
 
-            for name in only {
-                js.spawn(self.clone().run_service(name.clone(), cancel_token.clone()));
-            }
-        } else {
-            for (name, _) in &self.services {
-                js.spawn(self.clone().run_service(name.clone(), cancel_token.clone()));
-            }
+        if names.is_empty() && profiles.is_empty() {
+            selected = self
+                .services
+                .iter()
+                .filter(|(_, service)| service.autostart)
+                .map(|(name, _)| name.as_str())
+                .sorted()
+                .collect();
         }
 
-        select! {
-            _ = tokio::signal::ctrl_c() => {
-                println!("Shutting down services");
-            },
-            _ = js.join_next() => {}
+        let mut order = Vec::new();
+        for name in selected {
+            self.visit_service(name, &mut Vec::new(), &mut order)?;
         }
+        Ok(order)
+    }
 
-        cancel_token.cancel();
+    fn visit_service<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> anyhow::Result<()> {
+        if order.contains(&name) {
+            return Ok(());
+        }
+        if path.contains(&name) {
+            bail!(
+                "services depend on each other: {} -> {name}",
+                path.join(" -> ")
+            );
+        }
 
-        while js.join_next().await.is_some() {}
+        let Some(service) = self.services.get(name) else {
+            bail!("Service {name} does not exist");
+        };
 
+        path.push(name);
+        for dependency in &service.depends {
+            self.visit_service(dependency, path, order)
+                .with_context(|| format!("dependency of service {name}"))?;
+        }
+        path.pop();
+        order.push(name);
         Ok(())
     }
 
-    async fn monitor_outputs(
+    /// Implements `devit up`, which runs the selected services until interrupted, and fails if
+    /// any of them failed. With `tui`, they are shown in a dashboard instead of having their
+    /// output printed.
+    pub async fn run_services(
+        &self,
+        names: &[String],
+        profiles: &[String],
+        tui: bool,
+    ) -> anyhow::Result<()> {
+        if self.select_services(names, profiles)?.is_empty() {
+            println!("No services to bring up");
+            return Ok(());
+        }
+
+        let selection = Selection {
+            names: names.to_vec(),
+            profiles: profiles.to_vec(),
+        };
+        if tui {
+            Supervisor::run(self.clone(), selection, false, run_dashboard).await
+        } else {
+            Supervisor::run(self.clone(), selection, true, until_stopped).await
+        }
+    }
+
+    /// Prints the output of a child with each line prefixed by `name`, until both streams close.
+    pub async fn monitor_outputs(
         name: String,
         stdout: impl AsyncBufRead + Unpin + 'static,
         stderr: impl AsyncBufRead + Unpin + 'static,
     ) {
-        let mut stdout = stdout.lines();
-        let mut stderr = stderr.lines();
-
-        loop {
-            select! {
-                line = stdout.next_line() => {
-                    if let Ok(Some(line)) = line {
-                        println!("{name}: {line}");
-                    }
-                }
-
-                line = stderr.next_line() => {
-                    if let Ok(Some(line)) = line {
-                        eprintln!("{name}: {line}");
-                    }
-                }
+        read_lines(stdout, stderr, |line, from_stderr| {
+            if from_stderr {
+                eprintln!("{name}: {line}");
+            } else {
+                println!("{name}: {line}");
             }
-        }
+        })
+        .await
     }
 
-    pub async fn run_service(
-        self,
-        name: String,
-        cancellation: CancellationToken,
-    ) -> anyhow::Result<ExitStatus> {
+    /// Creates the command for one of the scripts of a service, which runs in the service
+    /// directory with the service environment.
+    pub fn service_command(
+        &self,
+        name: &str,
+        script: &Script,
+    ) -> anyhow::Result<(Command, Option<TempPath>)> {
         let service = self
             .services
-            .get(&name)
+            .get(name)
             .with_context(|| format!("Unable to find service {name}"))?;
 
-        std::fs::create_dir_all(&service.working_directory)
-            .with_context(|| format!("Error creating state directory for service {name}"))?;
-
-        let mut child = self
-            .run_command("sh", true)
-            .arg("-c")
-            .arg(&service.script)
-            .envs(service.environ.iter())
+        let (mut cmd, file) = match script.interpreter() {
+            // Services run with sh as they always have, where a failing command doesn't end the
+            // script
+            None => {
+                let mut cmd = self.run_command("sh", true);
+                cmd.arg("-c").arg(&script.body);
+                (cmd, None)
+            }
+            Some(_) => self.script_command(script)?,
+        };
+        cmd.envs(service.environ.iter())
+            .envs(
+                service
+                    .secrets
+                    .iter()
+                    .filter_map(|(name, secret)| Some((name, secret.value()?))),
+            )
             .current_dir(&service.working_directory)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("Spawning service")?;
-
-        let stdout = BufReader::new(child.stdout.take().context("taking out stdout")?);
-        let stderr = BufReader::new(child.stderr.take().context("taking out stderr")?);
-        let log_monitor = spawn(Self::monitor_outputs(name.clone(), stdout, stderr));
-
-        let pid: pid_t = child
-            .id()
-            .context("getting child process id")?
-            .try_into()
-            .context("converting to pid")?;
-
-        println!("Running service {name}");
-
-        let status: Option<ExitStatus> = select! {
-            _ = cancellation.cancelled() => None,
-            status = child.wait() => status.ok(),
+            .stdin(Stdio::null());
+        Ok((cmd, file))
+    }
+
+    /// Runs the readiness check of a service until it succeeds.
+    pub async fn wait_until_ready(&self, name: &str) -> anyhow::Result<()> {
+        let Some(ready) = self.services.get(name).and_then(|s| s.ready.as_ref()) else {
+            return Ok(());
         };
 
-        if status.is_none() {
-            println!("Terminating {name}");
-            unsafe {
-                kill(pid, SIGTERM);
+        loop {
+            let (mut cmd, _script_file) = self.service_command(name, ready)?;
+            let status = cmd
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .await
+                .context("running readiness check")?;
+            if status.success() {
+                return Ok(());
+            }
+
+            sleep(READY_CHECK_INTERVAL).await;
+        }
+    }
+}
+
+/// Waits until interrupted or a service stops, except for oneshot services completing. The
+/// services are stopped too once all of them are done.
+async fn until_stopped(
+    supervisor: Supervisor,
+    mut exits: UnboundedReceiver<String>,
+) -> anyhow::Result<()> {
+    let interrupted = interrupted();
+    tokio::pin!(interrupted);
+    loop {
+        select! {
+            _ = &mut interrupted => {
+                println!("Shutting down services");
+                return Ok(());
+            },
+            Some(name) = exits.recv() => {
+                let oneshot = supervisor
+                    .project()
+                    .services
+                    .get(&name)
+                    .is_some_and(|service| service.kind == ServiceKind::Oneshot);
+                match supervisor.status(&name) {
+                    Some(ServiceStatus::Completed) if oneshot => {
+                        if supervisor.all_done() {
+                            return Ok(());
+                        }
+                    }
+                    Some(ServiceStatus::Failed(reason)) if oneshot => {
+                        eprintln!("Service {name} failed with {reason}, stopping services");
+                        return Ok(());
+                    }
+                    _ => return Ok(()),
+                }
             }
         }
+    }
+}
 
-        println!("Gracefully waiting for {name} to terminate");
+/// Passes each line a child outputs to `on_line`, along with whether it comes from stderr,
+/// until both streams close.
+pub async fn read_lines(
+    stdout: impl AsyncBufRead + Unpin,
+    stderr: impl AsyncBufRead + Unpin,
+    mut on_line: impl FnMut(String, bool),
+) {
+    let mut stdout = stdout.lines();
+    let mut stderr = stderr.lines();
+    let (mut stdout_open, mut stderr_open) = (true, true);
 
-        let timeout_duration = Duration::from_secs(10);
-        match timeout(timeout_duration, child.wait()).await {
-            Err(_) => {
-                eprintln!("{name} doesn't respond within {timeout_duration:?}, killing...");
-                let _ = child.kill();
-                let _ = log_monitor.abort();
+    while stdout_open || stderr_open {
+        select! {
+            line = stdout.next_line(), if stdout_open => {
+                match line {
+                    Ok(Some(line)) => on_line(line, false),
+                    _ => stdout_open = false,
+                }
             }
 
-            Ok(status) => {
-                println!("{name} exited with status {status:?}");
-                let _ = child.kill().await;
-                let _ = log_monitor.abort();
-                return status.context("waiting for termination");
+            line = stderr.next_line(), if stderr_open => {
+                match line {
+                    Ok(Some(line)) => on_line(line, true),
+                    _ => stderr_open = false,
+                }
             }
         }
+    }
+}
 
-        Ok(ExitStatus::from_raw(0))
+/// Waits for Ctrl-C or SIGTERM, which CI runners and `timeout` send. Services run in their own
+/// process groups, so devit has to stop them itself on either.
+pub async fn interrupted() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Starts the command in a new process group, so it can be stopped along with everything it
+/// spawned. Such a child no longer receives Ctrl-C from the terminal.
+pub fn in_process_group(cmd: &mut Command) -> &mut Command {
+    // Safety: setpgid is async-signal-safe
+    unsafe {
+        cmd.pre_exec(|| match setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        })
+    }
+}
+
+/// Stops a child that was spawned in its own process group. The whole group is asked to
+/// terminate first, and killed if the child is still running after a grace period. Progress is
+/// reported through `log`.
+pub async fn terminate(
+    name: &str,
+    child: &mut Child,
+    log: impl Fn(String),
+) -> anyhow::Result<ExitStatus> {
+    let Some(pid) = child.id() else {
+        return child.wait().await.context("waiting for termination");
+    };
+    let pid: pid_t = pid.try_into().context("converting to pid")?;
+
+    unsafe {
+        killpg(pid, SIGTERM);
+    }
+
+    log(format!("Gracefully waiting for {name} to terminate"));
+
+    let timeout_duration = Duration::from_secs(10);
+    match timeout(timeout_duration, child.wait()).await {
+        Ok(status) => status.context("waiting for termination"),
+        Err(_) => {
+            log(format!(
+                "{name} doesn't respond within {timeout_duration:?}, killing..."
+            ));
+            unsafe {
+                killpg(pid, SIGKILL);
+            }
+            child.wait().await.context("waiting for termination")
+        }
     }
 }
@@ -1,80 +1,375 @@
-use crate::model::ProjectEnvironment;
+use crate::model::{PathMode, PathVar, ProjectEnvironment};
 use anyhow::Context;
+use itertools::Itertools;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::io::Write;
+use std::fs::write;
+use std::path::Path;
 use std::process::{exit, Stdio};
-use tempfile::NamedTempFile;
+use tempfile::TempDir;
 use tokio::process::Command;
 
+/// Records the entries devit added to each path variable
+const APPLIED_PATHS_VAR: &str = "DEVIT_APPLIED_PATHS";
+
+/// The variables kept in an isolated environment, besides the project's `passthrough`
+const ISOLATED_KEEP: &[&str] = &["HOME", "USER", "LOGNAME", "SHELL", "TERM", "LANG", "TMPDIR"];
+
+/// The PATH an isolated environment starts with
+const ISOLATED_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
+
+/// The entries of an inherited path variable, without those an enclosing devit environment
+/// added.
+fn inherited_entries<'a>(
+    existing: &'a str,
+    previous: &'a [String],
+) -> impl Iterator<Item = &'a str> + Clone {
+    existing
+        .split(':')
+        .filter(|e| !e.is_empty() && !previous.iter().any(|p| p == e))
+}
+
+/// Combines the entries of a path variable with the inherited value, leaving out the entries
+/// `previous` added and any duplicates.
+fn combine_paths(name: &str, var: Option<&PathVar>, existing: &str, previous: &[String]) -> String {
+    let inherited = inherited_entries(existing, previous);
+    let entries = var
+        .iter()
+        .flat_map(|v| v.entries.iter().map(String::as_str));
+
+    let value = match var.map(|v| v.mode) {
+        Some(PathMode::Replace) => entries.unique().join(":"),
+        Some(PathMode::Append) => inherited.chain(entries).unique().join(":"),
+        Some(PathMode::Prepend) | None => entries.chain(inherited).unique().join(":"),
+    };
+
+    // An empty MANPATH entry stands for the system's default search path
+    if name == "MANPATH"
+        && !value.is_empty()
+        && (existing.is_empty() || existing.split(':').any(str::is_empty))
+    {
+        format!("{value}:")
+    } else {
+        value
+    }
+}
+
+/// The entries of a path variable that weren't already inherited, which are the ones to take
+/// out again in a nested environment.
+fn added_entries(var: &PathVar, existing: &str, previous: &[String]) -> Vec<String> {
+    let inherited = inherited_entries(existing, previous);
+    var.entries
+        .iter()
+        .filter(|entry| !inherited.clone().any(|e| e == entry.as_str()))
+        .cloned()
+        .collect()
+}
+
 impl ProjectEnvironment {
+    fn is_inherited(&self, name: &str) -> bool {
+        !self.unset.iter().any(|n| n == name)
+            && (!self.isolate
+                || ISOLATED_KEEP.contains(&name)
+                || self.passthrough.iter().any(|n| n == name))
+    }
+
+    /// The value a variable has before devit's variables are applied
+    fn inherited_var(&self, name: &str) -> Option<String> {
+        if self.is_inherited(name) {
+            std::env::var(name).ok()
+        } else if self.isolate && name == "PATH" && !self.unset.iter().any(|n| n == name) {
+            Some(ISOLATED_PATH.to_string())
+        } else {
+            None
+        }
+    }
+
     pub fn run_command(&self, prog: impl AsRef<OsStr>, apply_user: bool) -> Command {
         let mut process = Command::new(prog);
-        for (name, value) in &self.environ {
-            let existing = std::env::var(name).unwrap_or_default();
-            if existing.is_empty() {
-                process.env(name, &value);
-            } else {
-                process.env(name, format!("{value}:{existing}"));
+        if self.isolate {
+            process.env_clear();
+            process.envs(std::env::vars().filter(|(name, _)| self.is_inherited(name)));
+            if let Some(path) = self.inherited_var("PATH") {
+                process.env("PATH", path);
             }
         }
 
+        for name in &self.unset {
+            process.env_remove(name);
+        }
+
+        // Entries added by an enclosing devit environment are taken out before adding ours, so
+        // nested shells and direnv reloads don't keep growing the variables.
+        let previous: HashMap<String, Vec<String>> = self
+            .inherited_var(APPLIED_PATHS_VAR)
+            .and_then(|v| serde_json::from_str(&v).ok())
+            .unwrap_or_default();
+
+        let mut applied = HashMap::new();
+        for name in self.environ.keys().chain(previous.keys()).unique() {
+            let existing = self.inherited_var(name).unwrap_or_default();
+            let previous = previous.get(name).map(Vec::as_slice).unwrap_or_default();
+            let var = self.environ.get(name);
+            if let Some(added) = var
+                .map(|var| added_entries(var, &existing, previous))
+                .filter(|added| !added.is_empty())
+            {
+                applied.insert(name, added);
+            }
+
+            let value = combine_paths(name, var, &existing, previous);
+            if value != existing {
+                process.env(name, value);
+            }
+        }
+
+        process.env(
+            APPLIED_PATHS_VAR,
+            serde_json::to_string(&applied).expect("to serialize paths"),
+        );
+
         if apply_user {
             for (name, value) in &self.user_environ {
-                process.env(name, &value);
+                process.env(name, value);
+            }
+
+            for (name, secret) in &self.secrets {
+                if let Some(value) = secret.value() {
+                    process.env(name, value);
+                }
             }
         }
         process
     }
 
     pub async fn run_shell(&self, command: Option<impl AsRef<str> + Debug>) -> anyhow::Result<()> {
-        let mut cmd = self.run_command("bash", true);
-
-        cmd.stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
-
         let status = if let Some(command) = command {
             let command = format!(
                 "set -e\n {}\n {}",
-                self.shell_hook
-                    .as_ref()
-                    .map(|s| s.as_str())
-                    .unwrap_or_default(),
+                self.shell_hook.as_deref().unwrap_or_default(),
                 command.as_ref()
             );
 
-            cmd.arg("-c")
+            self.run_command("bash", true)
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .arg("-c")
                 .arg(command)
                 .spawn()
                 .context("spawning shell")?
                 .wait()
                 .await
                 .context("wait for output")?
-        } else if let Some(hook) = self.shell_hook.as_ref() {
-            let file = NamedTempFile::new().context("Creating temp file")?;
-            let (mut file, path) = file.keep().context("keeping tempfile")?;
-            file.write_fmt(format_args!("rm -f {}\n", path.display()))
-                .context("writing tempfile")?;
-            file.write_all(hook.as_bytes()).context("writing hook")?;
-            drop(file);
+        } else {
+            // Holds the init files, which have to outlive the shell
+            let init_dir = TempDir::new().context("Creating temp dir")?;
 
             // Running as an interactive shell
-            let mut child = cmd
-                .arg("--rcfile")
-                .arg(path)
+            let status = self
+                .interactive_shell(init_dir.path())?
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
                 .spawn()
-                .context("spawning shell")?;
-
-            child.wait().await.context("wait for output")?
-        } else {
-            cmd.spawn()
                 .context("spawning shell")?
                 .wait()
                 .await
-                .context("wait for output")?
+                .context("wait for output")?;
+
+            drop(init_dir);
+            status
         };
 
         exit(status.code().unwrap_or_default());
     }
+
+    /// Sets up the user's shell to load their own rc file, then the project hook, and to show
+    /// the project name in the prompt.
+    fn interactive_shell(&self, init_dir: &Path) -> anyhow::Result<Command> {
+        let program = self
+            .shell_program
+            .clone()
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| String::from("bash"));
+        let hook = self.shell_hook.as_deref().unwrap_or_default();
+        let indicator = format!(
+            "({}) ",
+            self.project_dir
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("devit")
+        );
+
+        let mut cmd = self.run_command(&program, true);
+        match Path::new(&program).file_name().and_then(|n| n.to_str()) {
+            Some("bash") => {
+                let rcfile = init_dir.join("bashrc");
+                write(
+                    &rcfile,
+                    format!(
+                        "[ -f ~/.bashrc ] && . ~/.bashrc\n{hook}\nPS1={}\"$PS1\"\n",
+                        posix_quote(&indicator)
+                    ),
+                )
+                .context("writing bashrc")?;
+                cmd.arg("--rcfile").arg(rcfile);
+            }
+
+            Some("zsh") => {
+                // zsh reads its rc files from ZDOTDIR, which points to ours until they run. The
+                // user's files run with the user's ZDOTDIR, which their .zshenv may change
+                let restore = "if [ -n \"$DEVIT_ZDOTDIR\" ]; then ZDOTDIR=\"$DEVIT_ZDOTDIR\"; else unset ZDOTDIR; fi";
+                let user_dir = r#""${ZDOTDIR:-$HOME}""#;
+                write(
+                    init_dir.join(".zshenv"),
+                    format!(
+                        "{restore}\n\
+                         [ -f {user_dir}/.zshenv ] && . {user_dir}/.zshenv\n\
+                         DEVIT_ZDOTDIR=\"$ZDOTDIR\"\n\
+                         ZDOTDIR={}\n",
+                        posix_quote(init_dir.to_str().context("init path")?)
+                    ),
+                )
+                .context("writing .zshenv")?;
+                write(
+                    init_dir.join(".zshrc"),
+                    format!(
+                        "{restore}\n\
+                         unset DEVIT_ZDOTDIR\n\
+                         [ -f {user_dir}/.zshrc ] && . {user_dir}/.zshrc\n\
+                         {hook}\n\
+                         PROMPT={}\"$PROMPT\"\n",
+                        posix_quote(&indicator)
+                    ),
+                )
+                .context("writing .zshrc")?;
+
+                if let Ok(user_dir) = std::env::var("ZDOTDIR") {
+                    cmd.env("DEVIT_ZDOTDIR", user_dir);
+                }
+                cmd.env("ZDOTDIR", init_dir);
+            }
+
+            Some("fish") => {
+                // fish runs the init command after the user's config. The hook is written for
+                // POSIX shells, which fish can't run
+                if !hook.is_empty() {
+                    eprintln!("Warning: the shell hook is not run in fish");
+                }
+                let init = init_dir.join("init.fish");
+                write(
+                    &init,
+                    format!(
+                        "functions -q fish_prompt; and functions -c fish_prompt __devit_fish_prompt\n\
+                         function fish_prompt; echo -n {}; functions -q __devit_fish_prompt; and __devit_fish_prompt; end\n",
+                        fish_quote(&indicator)
+                    ),
+                )
+                .context("writing init.fish")?;
+                cmd.arg("--init-command").arg(format!(
+                    "source {}",
+                    fish_quote(init.to_str().context("init path")?)
+                ));
+            }
+
+            _ if !hook.is_empty() => {
+                eprintln!("Warning: {program} is not supported, the shell hook will not run");
+            }
+
+            _ => {}
+        }
+
+        Ok(cmd)
+    }
+}
+
+fn posix_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(entries: &[&str], mode: PathMode) -> PathVar {
+        PathVar {
+            entries: entries.iter().map(|e| e.to_string()).collect(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn prepends_entries() {
+        let path = var(&["/a", "/usr/bin"], PathMode::Prepend);
+        assert_eq!(
+            combine_paths("PATH", Some(&path), "/usr/bin:/bin", &[]),
+            "/a:/usr/bin:/bin"
+        );
+    }
+
+    #[test]
+    fn appends_entries() {
+        let path = var(&["/a", "/usr/bin"], PathMode::Append);
+        assert_eq!(
+            combine_paths("PATH", Some(&path), "/usr/bin:/bin", &[]),
+            "/usr/bin:/bin:/a"
+        );
+    }
+
+    #[test]
+    fn replaces_entries() {
+        let path = var(&["/a"], PathMode::Replace);
+        assert_eq!(
+            combine_paths("PATH", Some(&path), "/usr/bin:/bin", &[]),
+            "/a"
+        );
+    }
+
+    #[test]
+    fn removes_entries_added_by_an_enclosing_environment() {
+        let previous = ["/old".to_string()];
+        let path = var(&["/a"], PathMode::Prepend);
+        assert_eq!(
+            combine_paths("PATH", Some(&path), "/old:/usr/bin", &previous),
+            "/a:/usr/bin"
+        );
+        assert_eq!(
+            combine_paths("PATH", None, "/old:/usr/bin", &previous),
+            "/usr/bin"
+        );
+    }
+
+    #[test]
+    fn only_records_entries_that_were_not_inherited() {
+        let path = var(&["/a", "/usr/bin"], PathMode::Prepend);
+        assert_eq!(added_entries(&path, "/usr/bin:/bin", &[]), ["/a"]);
+
+        // The user's own /usr/bin is kept when a nested environment removes /a again
+        let previous = added_entries(&path, "/usr/bin:/bin", &[]);
+        assert_eq!(
+            combine_paths("PATH", None, "/a:/usr/bin:/bin", &previous),
+            "/usr/bin:/bin"
+        );
+    }
+
+    #[test]
+    fn keeps_the_default_manpath() {
+        let manpath = var(&["/a/man"], PathMode::Prepend);
+        assert_eq!(combine_paths("MANPATH", Some(&manpath), "", &[]), "/a/man:");
+        assert_eq!(
+            combine_paths("MANPATH", Some(&manpath), "/b/man:", &[]),
+            "/a/man:/b/man:"
+        );
+        assert_eq!(
+            combine_paths("MANPATH", Some(&manpath), "/b/man", &[]),
+            "/a/man:/b/man"
+        );
+        assert_eq!(combine_paths("PATH", Some(&manpath), "", &[]), "/a/man");
+    }
 }
@@ -0,0 +1,890 @@
+use crate::control::{self, CONTROL_SOCKET};
+use crate::model::{ProjectEnvironment, Script, ServiceKind};
+use crate::service::{in_process_group, read_lines, terminate};
+use crate::watch::{
+    file_watcher, next_change, watch_files, whole_dir, FileFilter, DEFAULT_DEBOUNCE,
+};
+use anyhow::{bail, Context};
+use derive_more::Display;
+use glob::Pattern;
+use indexmap::IndexMap;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, BufReader};
+use tokio::process::Child;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
+
+/// How long a service has to exit after its stop command.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many lines of output are kept for each service.
+const SCROLLBACK: usize = 10_000;
+
+/// What a service is doing.
+#[derive(Debug, Clone, Default, Display, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// Waiting for the services it depends on
+    #[default]
+    #[display(fmt = "waiting")]
+    Waiting,
+    /// Running its init command
+    #[display(fmt = "initializing")]
+    Initializing,
+    #[display(fmt = "running")]
+    Running,
+    /// Running, and its readiness check passed
+    #[display(fmt = "ready")]
+    Ready,
+    #[display(fmt = "stopping")]
+    Stopping,
+    /// Stopped by devit
+    #[display(fmt = "stopped")]
+    Stopped,
+    /// Exited successfully
+    #[display(fmt = "completed")]
+    Completed,
+    /// Exited with an error, or couldn't be started
+    #[display(fmt = "failed ({})", _0)]
+    Failed(String),
+}
+
+/// Where a line of a service log comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogKind {
+    Stdout,
+    Stderr,
+    /// A message of devit about the service
+    Message,
+    /// An error of devit about the service
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub kind: LogKind,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServiceState {
+    pub status: ServiceStatus,
+    pub pid: Option<u32>,
+    /// How many times the service was restarted on request
+    pub restarts: u32,
+    /// The latest output of the service and messages about it
+    pub logs: VecDeque<LogLine>,
+}
+
+/// Requests to a supervised service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Starts the service unless it is running
+    Start,
+    Stop,
+    Restart,
+}
+
+/// The services asked for on the command line. They are selected again whenever the project
+/// is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub names: Vec<String>,
+    pub profiles: Vec<String>,
+}
+
+/// Whether the services depending on a service can start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Availability {
+    Pending,
+    Ready,
+    /// Failed before it was ready, or as a oneshot service
+    Failed,
+}
+
+/// Tells services when the services they depend on are ready.
+#[derive(Clone, Default)]
+struct Readiness(Arc<Mutex<HashMap<String, watch::Sender<Availability>>>>);
+
+impl Readiness {
+    fn add(&self, name: &str) {
+        let mut senders = self.0.lock().unwrap();
+        if !senders.contains_key(name) {
+            senders.insert(name.to_string(), watch::channel(Availability::Pending).0);
+        }
+    }
+
+    fn set(&self, name: &str, availability: Availability) {
+        if let Some(sender) = self.0.lock().unwrap().get(name) {
+            sender.send_replace(availability);
+        }
+    }
+
+    fn is_ready(&self, name: &str) -> bool {
+        let senders = self.0.lock().unwrap();
+        senders
+            .get(name)
+            .is_some_and(|sender| *sender.borrow() == Availability::Ready)
+    }
+
+    /// Waits until the service is ready, and fails if it failed instead. Services that aren't
+    /// started are never ready.
+    async fn wait(&self, name: &str) -> anyhow::Result<()> {
+        let receiver = self
+            .0
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(watch::Sender::subscribe);
+        let Some(mut receiver) = receiver else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            match *receiver.borrow_and_update() {
+                Availability::Ready => return Ok(()),
+                Availability::Failed => bail!("dependency {name} failed"),
+                Availability::Pending => {}
+            }
+            if receiver.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+/// Runs services, starting, stopping or restarting them on request, and keeps track of their
+/// status and output.
+#[derive(Clone)]
+pub struct Supervisor {
+    /// The project as last read, replaced when its files change
+    project: Arc<watch::Sender<Arc<ProjectEnvironment>>>,
+    states: Arc<Mutex<IndexMap<String, ServiceState>>>,
+    controls: Arc<Mutex<HashMap<String, UnboundedSender<Control>>>>,
+    readiness: Readiness,
+    /// What happened to the project last, until it is taken
+    announcement: Arc<Mutex<Option<String>>>,
+    /// Services to start supervising on request
+    additions: UnboundedSender<String>,
+    print: bool,
+}
+
+impl Supervisor {
+    /// Supervises the selected services until `driver` returns, then stops them all. The
+    /// services are started dependencies first, and the project is reloaded whenever its files
+    /// change. The driver is told the name of every service that exits on its own. With
+    /// `print`, the output of the services and the messages about them are printed as they
+    /// come. Once they are stopped, how each of them ended up is printed, and it fails if any
+    /// of them failed.
+    pub async fn run<F, Fut>(
+        project: ProjectEnvironment,
+        selection: Selection,
+        print: bool,
+        driver: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(Supervisor, UnboundedReceiver<String>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let selected: Vec<String> = project
+            .select_services(&selection.names, &selection.profiles)?
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let (additions, mut added) = unbounded_channel();
+        let supervisor = Supervisor {
+            project: Arc::new(watch::channel(Arc::new(project)).0),
+            states: Default::default(),
+            controls: Default::default(),
+            readiness: Default::default(),
+            announcement: Default::default(),
+            additions,
+            print,
+        };
+
+        let state_dir = supervisor.project().state_dir.clone();
+        let server = match control::listen(&state_dir) {
+            Ok(listener) => Some(spawn(supervisor.clone().serve(listener))),
+            Err(e) => {
+                eprintln!("Services can't be controlled with devit start, stop or restart: {e:#}");
+                None
+            }
+        };
+
+        let shutdown = CancellationToken::new();
+        let (exits, exits_receiver) = unbounded_channel();
+        let mut tasks = JoinSet::new();
+        for name in selected {
+            supervisor.add(name, &mut tasks, &exits, &shutdown);
+        }
+        tasks.spawn(
+            supervisor
+                .clone()
+                .reload(selection, exits.clone(), shutdown.clone()),
+        );
+
+        let driver = driver(supervisor.clone(), exits_receiver);
+        tokio::pin!(driver);
+        let result = loop {
+            select! {
+                result = &mut driver => break result,
+                Some(name) = added.recv() => {
+                    // The same service may have been asked for twice in a row
+                    if supervisor.status(&name).is_none() {
+                        supervisor.add(name, &mut tasks, &exits, &shutdown);
+                    }
+                }
+            }
+        };
+
+        shutdown.cancel();
+        let mut crashed = false;
+        while let Some(joined) = tasks.join_next().await {
+            if let Err(e) = joined {
+                eprintln!("Supervising services crashed: {e}");
+                crashed = true;
+            }
+        }
+
+        if let Some(server) = server {
+            server.abort();
+            let _ = std::fs::remove_file(state_dir.join(CONTROL_SOCKET));
+        }
+
+        let failed = supervisor.with_states(|states| {
+            print_summary(states);
+            states
+                .iter()
+                .filter(|(_, state)| matches!(state.status, ServiceStatus::Failed(_)))
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        result?;
+        if !failed.is_empty() {
+            bail!("Services failed: {failed}");
+        }
+        if crashed {
+            bail!("Some services could not be supervised until the end");
+        }
+        Ok(())
+    }
+
+    /// The project as last read.
+    pub fn project(&self) -> Arc<ProjectEnvironment> {
+        self.project.borrow().clone()
+    }
+
+    /// The supervised services, in the order they were started.
+    pub fn names(&self) -> Vec<String> {
+        self.states.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn status(&self, name: &str) -> Option<ServiceStatus> {
+        let states = self.states.lock().unwrap();
+        states.get(name).map(|state| state.status.clone())
+    }
+
+    /// Whether the service is ready for the services depending on it.
+    pub fn is_ready(&self, name: &str) -> bool {
+        self.readiness.is_ready(name)
+    }
+
+    /// Whether every service has exited or was stopped.
+    pub fn all_done(&self) -> bool {
+        self.states.lock().unwrap().values().all(|state| {
+            matches!(
+                state.status,
+                ServiceStatus::Stopped | ServiceStatus::Completed | ServiceStatus::Failed(_)
+            )
+        })
+    }
+
+    /// Gives access to the state of all services without copying their logs.
+    pub fn with_states<R>(&self, f: impl FnOnce(&IndexMap<String, ServiceState>) -> R) -> R {
+        f(&self.states.lock().unwrap())
+    }
+
+    pub fn control(&self, name: &str, control: Control) -> anyhow::Result<()> {
+        let controls = self.controls.lock().unwrap();
+        let Some(controls) = controls.get(name) else {
+            bail!("Service {name} is not running under devit");
+        };
+        controls
+            .send(control)
+            .with_context(|| format!("Service {name} is no longer supervised"))
+    }
+
+    /// Starts supervising a service of the project that isn't supervised yet, along with the
+    /// services it depends on that aren't either.
+    pub fn start(&self, name: &str) -> anyhow::Result<()> {
+        let project = self.project();
+        for name in project.select_services(&[name.to_string()], &[])? {
+            if self.status(name).is_none() {
+                self.additions
+                    .send(name.to_string())
+                    .context("devit up is shutting down")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes what happened to the project since last time, if anything.
+    pub fn take_announcement(&self) -> Option<String> {
+        self.announcement.lock().unwrap().take()
+    }
+
+    /// Starts supervising a service, which is started right away.
+    fn add(
+        &self,
+        name: String,
+        tasks: &mut JoinSet<()>,
+        exits: &UnboundedSender<String>,
+        shutdown: &CancellationToken,
+    ) {
+        let (sender, receiver) = unbounded_channel();
+        self.controls.lock().unwrap().insert(name.clone(), sender);
+        self.states
+            .lock()
+            .unwrap()
+            .insert(name.clone(), ServiceState::default());
+        self.readiness.add(&name);
+        tasks.spawn(
+            self.clone()
+                .supervise(name, receiver, exits.clone(), shutdown.clone()),
+        );
+    }
+
+    /// Stops a service, and supervising it once it is stopped.
+    fn remove(&self, name: &str) {
+        if let Some(controls) = self.controls.lock().unwrap().remove(name) {
+            let _ = controls.send(Control::Stop);
+        }
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut ServiceState)) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(name) {
+            f(state);
+        }
+    }
+
+    fn set_status(&self, name: &str, status: ServiceStatus) {
+        self.update(name, |state| state.status = status);
+    }
+
+    fn log(&self, name: &str, kind: LogKind, text: String) {
+        if self.print {
+            match kind {
+                LogKind::Stdout => println!("{name}: {text}"),
+                LogKind::Stderr => eprintln!("{name}: {text}"),
+                LogKind::Message => println!("{text}"),
+                LogKind::Error => eprintln!("{text}"),
+            }
+        }
+
+        self.update(name, |state| {
+            state.logs.push_back(LogLine { kind, text });
+            if state.logs.len() > SCROLLBACK {
+                state.logs.pop_front();
+            }
+        });
+    }
+
+    fn message(&self, name: &str, text: String) {
+        self.log(name, LogKind::Message, text);
+    }
+
+    fn error(&self, name: &str, text: String) {
+        self.log(name, LogKind::Error, text);
+    }
+
+    /// Tells the user about something that happened to the project rather than to a service.
+    fn announce(&self, kind: LogKind, text: String) {
+        if self.print {
+            match kind {
+                LogKind::Error | LogKind::Stderr => eprintln!("{text}"),
+                LogKind::Message | LogKind::Stdout => println!("{text}"),
+            }
+        }
+        *self.announcement.lock().unwrap() = Some(text);
+    }
+
+    /// Runs a service whenever it is asked to, starting with right away, until shut down or
+    /// removed.
+    async fn supervise(
+        self,
+        name: String,
+        mut controls: UnboundedReceiver<Control>,
+        exits: UnboundedSender<String>,
+        shutdown: CancellationToken,
+    ) {
+        let watcher = spawn(self.clone().watch(name.clone()));
+        let mut start = true;
+        while !shutdown.is_cancelled() {
+            if !start {
+                select! {
+                    _ = shutdown.cancelled() => break,
+                    control = controls.recv() => match control {
+                        Some(control) => {
+                            start = matches!(control, Control::Start | Control::Restart);
+                        }
+                        None => break,
+                    }
+                }
+                continue;
+            }
+
+            start = false;
+            let stop = shutdown.child_token();
+            let run = self.run_service(&name, &stop);
+            tokio::pin!(run);
+            let result = loop {
+                select! {
+                    result = &mut run => break result,
+                    Some(control) = controls.recv() => match control {
+                        Control::Start => {}
+                        Control::Stop => stop.cancel(),
+                        Control::Restart => {
+                            stop.cancel();
+                            start = true;
+                        }
+                    }
+                }
+            };
+
+            let status = match result {
+                _ if stop.is_cancelled() => ServiceStatus::Stopped,
+                Ok(status) if status.success() => ServiceStatus::Completed,
+                Ok(status) => ServiceStatus::Failed(status.to_string()),
+                Err(e) => {
+                    self.error(&name, format!("Service {name} failed: {e:?}"));
+                    ServiceStatus::Failed(e.to_string())
+                }
+            };
+
+            // A completed oneshot service stays ready for the services depending on it, and those
+            // still waiting for a failed service fail as well rather than wait forever
+            let availability = match status {
+                ServiceStatus::Completed => Availability::Ready,
+                ServiceStatus::Failed(_) => Availability::Failed,
+                _ => Availability::Pending,
+            };
+            self.readiness.set(&name, availability);
+            self.update(&name, |state| {
+                state.status = status;
+                state.pid = None;
+                if start {
+                    state.restarts += 1;
+                }
+            });
+
+            if !stop.is_cancelled() {
+                let _ = exits.send(name.clone());
+            }
+        }
+
+        watcher.abort();
+        // A removed service is gone from the list, unless it was added back in the meantime
+        if !self.controls.lock().unwrap().contains_key(&name) {
+            self.states.lock().unwrap().shift_remove(&name);
+        }
+    }
+
+    /// Restarts a service whenever the files it watches change, unless it was stopped.
+    async fn watch(self, name: String) {
+        if let Err(e) = self.restart_on_changes(&name).await {
+            self.error(&name, format!("Unable to watch the files of {name}: {e:?}"));
+        }
+    }
+
+    /// Watches the files of a service as the project says, starting over when it is reloaded.
+    async fn restart_on_changes(&self, name: &str) -> anyhow::Result<()> {
+        let mut updates = self.project.subscribe();
+        loop {
+            let project = updates.borrow_and_update().clone();
+            let Some(service) = project.services.get(name) else {
+                return Ok(());
+            };
+            if service.watch.is_empty() {
+                updates.changed().await?;
+                continue;
+            }
+
+            // Services write to the state directory all the time
+            let state_dir = whole_dir(&project.state_dir);
+            let ignore: Vec<_> = service.ignore.iter().cloned().chain(state_dir).collect();
+            let filter = FileFilter::new(&service.watch, &ignore)?;
+            let (_watcher, mut changes) = watch_files(&project.project_dir)?;
+
+            loop {
+                select! {
+                    result = updates.changed() => {
+                        result?;
+                        break;
+                    }
+                    _ = next_change(&mut changes, &filter, service.debounce) => {}
+                }
+
+                if matches!(
+                    self.status(name),
+                    Some(ServiceStatus::Stopping | ServiceStatus::Stopped)
+                ) {
+                    continue;
+                }
+
+                self.message(name, format!("Files changed, restarting {name}"));
+                self.control(name, Control::Restart)?;
+            }
+        }
+    }
+
+    /// Reads the project again whenever one of its files changes, until shut down.
+    async fn reload(
+        self,
+        selection: Selection,
+        exits: UnboundedSender<String>,
+        shutdown: CancellationToken,
+    ) {
+        // The services added along the way
+        let mut tasks = JoinSet::new();
+        select! {
+            _ = shutdown.cancelled() => {}
+            Err(e) = self.reload_on_changes(&selection, &mut tasks, &exits, &shutdown) => {
+                self.announce(LogKind::Error, format!("Unable to watch the project files: {e:?}"));
+            }
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    async fn reload_on_changes(
+        &self,
+        selection: &Selection,
+        tasks: &mut JoinSet<()>,
+        exits: &UnboundedSender<String>,
+        shutdown: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        // Editors often replace files rather than write them, so their directories are watched.
+        // The watcher lives as long as the task so that changes made during a reload count.
+        let (mut watcher, mut changes) = file_watcher()?;
+        let mut watched = HashSet::new();
+        loop {
+            let project = self.project();
+            for dir in project.source_files.iter().filter_map(|file| file.parent()) {
+                if watched.insert(dir.to_path_buf()) {
+                    watcher
+                        .watch(dir, RecursiveMode::NonRecursive)
+                        .with_context(|| format!("watching {}", dir.display()))?;
+                }
+            }
+
+            let files: Vec<_> = project
+                .source_files
+                .iter()
+                .map(|file| Pattern::escape(&file.to_string_lossy()))
+                .collect();
+            let filter = FileFilter::new(&files, &[])?;
+            next_change(&mut changes, &filter, DEFAULT_DEBOUNCE).await;
+
+            let toml_file = project.source_files.first().context("no project file")?;
+            let result = crate::reload_project(toml_file)
+                .await
+                .and_then(|reloaded| self.apply(reloaded, selection, tasks, exits, shutdown));
+            match result {
+                Ok(summary) => self.announce(
+                    LogKind::Message,
+                    format!("Reloaded {}: {summary}", toml_file.display()),
+                ),
+                Err(e) => self.announce(
+                    LogKind::Error,
+                    format!(
+                        "Unable to reload {}, services are left as they were: {e:#}",
+                        toml_file.display()
+                    ),
+                ),
+            }
+        }
+    }
+
+    /// Switches to a new version of the project: services that changed are restarted, unless
+    /// they were stopped, those now selected are started and those gone from the project are
+    /// stopped. Returns what was done.
+    fn apply(
+        &self,
+        reloaded: ProjectEnvironment,
+        selection: &Selection,
+        tasks: &mut JoinSet<()>,
+        exits: &UnboundedSender<String>,
+        shutdown: &CancellationToken,
+    ) -> anyhow::Result<String> {
+        // Services named on the command line may have been removed since
+        let names: Vec<_> = selection
+            .names
+            .iter()
+            .filter(|name| reloaded.services.contains_key(*name))
+            .cloned()
+            .collect();
+        let selected: Vec<String> = reloaded
+            .select_services(&names, &selection.profiles)?
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let reloaded = Arc::new(reloaded);
+        // Everything started from now on runs as the new project says
+        let previous = self.project.send_replace(reloaded.clone());
+        let running: Vec<_> = {
+            let controls = self.controls.lock().unwrap();
+            self.names()
+                .into_iter()
+                .filter(|name| controls.contains_key(name))
+                .collect()
+        };
+
+        // Services also run with the environment of the project
+        let environment_changed = previous.environ != reloaded.environ
+            || previous.unset != reloaded.unset
+            || previous.user_environ != reloaded.user_environ
+            || previous.secrets != reloaded.secrets;
+
+        let (mut restarted, mut started, mut stopped) = (Vec::new(), Vec::new(), Vec::new());
+        for name in &running {
+            match reloaded.services.get(name) {
+                None => {
+                    self.remove(name);
+                    stopped.push(name.as_str());
+                }
+                Some(service)
+                    if environment_changed || previous.services.get(name) != Some(service) =>
+                {
+                    if self.status(name) != Some(ServiceStatus::Stopped) {
+                        self.message(name, format!("Service {name} changed, restarting it"));
+                        self.control(name, Control::Restart)?;
+                        restarted.push(name.as_str());
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        for name in selected {
+            if !running.contains(&name) {
+                started.push(name.clone());
+                self.add(name, tasks, exits, shutdown);
+            }
+        }
+
+        let mut summary = Vec::new();
+        if !restarted.is_empty() {
+            summary.push(format!("restarted {}", restarted.join(", ")));
+        }
+        if !started.is_empty() {
+            summary.push(format!("started {}", started.join(", ")));
+        }
+        if !stopped.is_empty() {
+            summary.push(format!("stopped {}", stopped.join(", ")));
+        }
+        if summary.is_empty() {
+            summary.push("no service changed".to_string());
+        }
+        Ok(summary.join(", "))
+    }
+
+    /// Runs a service once its dependencies are ready, until it exits or is cancelled.
+    async fn run_service(
+        &self,
+        name: &str,
+        cancellation: &CancellationToken,
+    ) -> anyhow::Result<ExitStatus> {
+        let project = self.project();
+        let service = project
+            .services
+            .get(name)
+            .with_context(|| format!("Unable to find service {name}"))?;
+
+        self.set_status(name, ServiceStatus::Waiting);
+        self.readiness.set(name, Availability::Pending);
+        for dependency in &service.depends {
+            if self.readiness.is_ready(dependency) {
+                continue;
+            }
+
+            self.message(name, format!("Service {name} is waiting for {dependency}"));
+            select! {
+                _ = cancellation.cancelled() => return Ok(ExitStatus::default()),
+                result = self.readiness.wait(dependency) => result?,
+            }
+        }
+
+        let first_start = !service.working_directory.exists();
+        std::fs::create_dir_all(&service.working_directory)
+            .with_context(|| format!("Error creating state directory for service {name}"))?;
+
+        if let Some(init) = service.init.as_ref().filter(|_| first_start) {
+            self.set_status(name, ServiceStatus::Initializing);
+            self.message(name, format!("Initializing service {name}"));
+            let status = self.run_command(&project, name, init).await;
+            if !status.as_ref().is_ok_and(ExitStatus::success) {
+                // Start from scratch next time rather than with a half initialized directory
+                let _ = std::fs::remove_dir_all(&service.working_directory);
+                bail!("Initializing service {name} failed: {status:?}");
+            }
+        }
+
+        let (mut cmd, _script_file) = project.service_command(name, &service.script)?;
+        let mut child = in_process_group(&mut cmd)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Spawning service")?;
+
+        let stdout = BufReader::new(child.stdout.take().context("taking out stdout")?);
+        let stderr = BufReader::new(child.stderr.take().context("taking out stderr")?);
+        let log_monitor = spawn(
+            self.clone()
+                .capture_output(name.to_string(), stdout, stderr),
+        );
+
+        let pid = child.id();
+        self.update(name, |state| {
+            state.status = ServiceStatus::Running;
+            state.pid = pid;
+        });
+        self.message(name, format!("Running service {name}"));
+
+        let ready = project.wait_until_ready(name);
+        tokio::pin!(ready);
+        let oneshot = service.kind == ServiceKind::Oneshot;
+        let mut waiting_ready = service.ready.is_some() && !oneshot;
+        if !waiting_ready && !oneshot {
+            self.readiness.set(name, Availability::Ready);
+        }
+
+        let status = loop {
+            select! {
+                _ = cancellation.cancelled() => {
+                    break self.stop_service(&project, name, &mut child).await;
+                },
+                status = child.wait() => break status.context("waiting for service"),
+                ready = &mut ready, if waiting_ready => {
+                    waiting_ready = false;
+                    match ready {
+                        Ok(()) => {
+                            self.set_status(name, ServiceStatus::Ready);
+                            self.message(name, format!("Service {name} is ready"));
+                        }
+                        Err(e) => {
+                            self.error(name, format!("Unable to check whether {name} is ready: {e:?}"));
+                        }
+                    }
+                    self.readiness.set(name, Availability::Ready);
+                }
+            }
+        };
+
+        if oneshot && !cancellation.is_cancelled() {
+            // Show all the output of a job, especially when it failed
+            let _ = log_monitor.await;
+        } else {
+            log_monitor.abort();
+        }
+        if let Ok(status) = &status {
+            self.message(name, format!("{name} exited with status {status:?}"));
+            if oneshot && status.success() {
+                self.message(name, format!("Service {name} completed"));
+            }
+        }
+        status
+    }
+
+    async fn capture_output(
+        self,
+        name: String,
+        stdout: impl AsyncBufRead + Unpin,
+        stderr: impl AsyncBufRead + Unpin,
+    ) {
+        read_lines(stdout, stderr, |line, from_stderr| {
+            let kind = if from_stderr {
+                LogKind::Stderr
+            } else {
+                LogKind::Stdout
+            };
+            self.log(&name, kind, line);
+        })
+        .await
+    }
+
+    /// Runs a command of a service to completion, with its output going to the service log.
+    async fn run_command(
+        &self,
+        project: &ProjectEnvironment,
+        name: &str,
+        script: &Script,
+    ) -> anyhow::Result<ExitStatus> {
+        let (mut cmd, _script_file) = project.service_command(name, script)?;
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Spawning command of service {name}"))?;
+
+        let stdout = BufReader::new(child.stdout.take().context("taking out stdout")?);
+        let stderr = BufReader::new(child.stderr.take().context("taking out stderr")?);
+        let log_monitor = spawn(
+            self.clone()
+                .capture_output(name.to_string(), stdout, stderr),
+        );
+
+        let status = child.wait().await.context("waiting for command");
+        let _ = log_monitor.await;
+        status
+    }
+
+    /// Stops a service with its stop command if it has one, falling back to signals if that
+    /// doesn't work.
+    async fn stop_service(
+        &self,
+        project: &ProjectEnvironment,
+        name: &str,
+        child: &mut Child,
+    ) -> anyhow::Result<ExitStatus> {
+        self.set_status(name, ServiceStatus::Stopping);
+        if let Some(stop) = project.services.get(name).and_then(|s| s.stop.as_ref()) {
+            self.message(name, format!("Stopping {name}"));
+            match self.run_command(project, name, stop).await {
+                Ok(status) if status.success() => {
+                    if let Ok(status) = timeout(STOP_TIMEOUT, child.wait()).await {
+                        return status.context("waiting for service to stop");
+                    }
+                }
+                result => self.error(name, format!("Stop command of {name} failed: {result:?}")),
+            }
+        }
+
+        self.message(name, format!("Terminating {name}"));
+        terminate(name, child, |message| self.message(name, message)).await
+    }
+}
+
+/// Prints how each service ended up once `devit up` is done.
+fn print_summary(states: &IndexMap<String, ServiceState>) {
+    let width = states
+        .keys()
+        .map(String::len)
+        .chain(["SERVICE".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!("{:width$}  {:8}  STATUS", "SERVICE", "RESTARTS");
+    for (name, state) in states {
+        println!("{name:width$}  {:8}  {}", state.restarts, state.status);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::service::interrupted;
+use crate::supervisor::{Control, LogKind, LogLine, ServiceStatus, Supervisor};
+use anyhow::Context;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::interval;
+
+/// How often the dashboard is redrawn without any input.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+const HELP: &str = "q quit  ↑↓ select  s start  x stop  r restart  / search  n/N older/newer match  PgUp/PgDn scroll";
+
+/// Implements `devit up --tui`, a dashboard of the services with their logs, until the user
+/// quits.
+pub async fn run_dashboard(
+    supervisor: Supervisor,
+    exits: UnboundedReceiver<String>,
+) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init().context("setting up the terminal")?;
+    let result = Dashboard::new(supervisor).run(&mut terminal, exits).await;
+    ratatui::restore();
+    result
+}
+
+struct Dashboard {
+    supervisor: Supervisor,
+    names: Vec<String>,
+    selected: usize,
+    /// How many lines the log is scrolled up from its end
+    scroll: usize,
+    log_height: usize,
+    /// The search being typed
+    input: Option<String>,
+    search: Option<String>,
+    /// The outcome of the last action, shown instead of the help
+    notice: Option<String>,
+}
+
+impl Dashboard {
+    fn new(supervisor: Supervisor) -> Self {
+        Self {
+            names: supervisor.names(),
+            supervisor,
+            selected: 0,
+            scroll: 0,
+            log_height: 0,
+            input: None,
+            search: None,
+            notice: None,
+        }
+    }
+
+    async fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+        mut exits: UnboundedReceiver<String>,
+    ) -> anyhow::Result<()> {
+        let mut events = read_events();
+        let mut refresh = interval(REFRESH_INTERVAL);
+        let interrupted = interrupted();
+        tokio::pin!(interrupted);
+
+        loop {
+            if let Some(announcement) = self.supervisor.take_announcement() {
+                self.notice = Some(announcement.replace('\n', " "));
+            }
+            terminal
+                .draw(|frame| self.draw(frame))
+                .context("drawing the dashboard")?;
+
+            select! {
+                _ = refresh.tick() => {}
+                _ = &mut interrupted => return Ok(()),
+                Some(name) = exits.recv() => self.notice = Some(self.exit_notice(&name)),
+                event = events.recv() => match event {
+                    Some(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        if !self.handle_key(key) {
+                            return Ok(());
+                        }
+                    }
+                    Some(_) => {}
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Describes how a service that exited on its own ended.
+    fn exit_notice(&self, name: &str) -> String {
+        match self.supervisor.status(name) {
+            Some(ServiceStatus::Failed(reason)) => format!("Service {name} failed with {reason}"),
+            Some(ServiceStatus::Completed) => format!("Service {name} completed"),
+            _ => format!("Service {name} exited"),
+        }
+    }
+
+    /// Acts on a key press. Returns false when the user quits.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Enter => {
+                    self.search = Some(std::mem::take(input)).filter(|s| !s.is_empty());
+                    self.input = None;
+                    self.scroll = 0;
+                    self.find_match(true, true);
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        self.notice = None;
+        match key.code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.select(self.selected.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => self.select(self.selected + 1),
+            KeyCode::PageUp => self.scroll += self.log_height,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.log_height),
+            KeyCode::Home | KeyCode::Char('g') => self.scroll = usize::MAX,
+            KeyCode::End | KeyCode::Char('G') => self.scroll = 0,
+            KeyCode::Char('/') => self.input = Some(String::new()),
+            KeyCode::Char('n') => self.find_match(true, false),
+            KeyCode::Char('N') => self.find_match(false, false),
+            KeyCode::Esc => self.search = None,
+            KeyCode::Char('s') => self.control(Control::Start),
+            KeyCode::Char('x') => self.control(Control::Stop),
+            KeyCode::Char('r') => self.control(Control::Restart),
+            _ => {}
+        }
+        true
+    }
+
+    fn select(&mut self, index: usize) {
+        let index = index.min(self.names.len().saturating_sub(1));
+        if index != self.selected {
+            self.selected = index;
+            self.scroll = 0;
+        }
+    }
+
+    fn control(&mut self, control: Control) {
+        let Some(name) = self.names.get(self.selected) else {
+            return;
+        };
+        self.notice = Some(match self.supervisor.control(name, control) {
+            Ok(()) => match control {
+                Control::Start => format!("Starting {name}"),
+                Control::Stop => format!("Stopping {name}"),
+                Control::Restart => format!("Restarting {name}"),
+            },
+            Err(e) => format!("{e:#}"),
+        });
+    }
+
+    /// Scrolls the log so that the next line matching the search is at the bottom, looking at
+    /// older or newer lines than the one at the bottom, or that one too with `inclusive`.
+    fn find_match(&mut self, older: bool, inclusive: bool) {
+        let Some(search) = self.search.as_ref().map(|s| s.to_lowercase()) else {
+            return;
+        };
+
+        let scroll = self.scroll;
+        let found = self.supervisor.with_states(|states| {
+            let logs = &states.get(self.names.get(self.selected)?)?.logs;
+            let is_match = |i: &usize| logs[*i].text.to_lowercase().contains(&search);
+            // The line at the bottom of the view is the one before `end`
+            let end = logs.len().saturating_sub(scroll);
+            let found = if older {
+                let before = if inclusive {
+                    end
+                } else {
+                    end.saturating_sub(1)
+                };
+                (0..before).rev().find(is_match)
+            } else {
+                (end..logs.len()).find(is_match)
+            };
+            found.map(|i| logs.len() - 1 - i)
+        });
+
+        match found {
+            Some(scroll) => self.scroll = scroll,
+            None => self.notice = Some(format!("No more matches for '{search}'")),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let supervisor = self.supervisor.clone();
+        let footer_area = supervisor.with_states(|states| {
+            // Services come and go as the project is reloaded
+            self.names = states.keys().cloned().collect();
+            self.select(self.selected);
+
+            let [services_area, log_area, footer_area] = Layout::vertical([
+                Constraint::Length(self.names.len() as u16 + 3),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+
+            let rows = self.names.iter().map(|name| {
+                let state = &states[name];
+                Row::new([
+                    name.clone(),
+                    state.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    state.restarts.to_string(),
+                    if supervisor.is_ready(name) {
+                        "yes"
+                    } else {
+                        "no"
+                    }
+                    .to_string(),
+                    state.status.to_string(),
+                ])
+                .style(status_style(&state.status))
+            });
+            let name_width = self.names.iter().map(String::len).max().unwrap_or_default();
+            let services = Table::new(
+                rows,
+                [
+                    Constraint::Length(name_width.max(7) as u16),
+                    Constraint::Length(8),
+                    Constraint::Length(8),
+                    Constraint::Length(5),
+                    Constraint::Fill(1),
+                ],
+            )
+            .header(Row::new(["Service", "PID", "Restarts", "Ready", "Status"]).bold())
+            .block(Block::bordered().title(" Services "))
+            .row_highlight_style(Style::new().reversed());
+            frame.render_stateful_widget(
+                services,
+                services_area,
+                &mut TableState::new().with_selected(Some(self.selected)),
+            );
+
+            let Some(name) = self.names.get(self.selected) else {
+                return footer_area;
+            };
+            let logs = &states[name].logs;
+            self.log_height = log_area.height.saturating_sub(2) as usize;
+            self.scroll = self.scroll.min(logs.len().saturating_sub(self.log_height));
+            let end = logs.len() - self.scroll;
+            let start = end.saturating_sub(self.log_height);
+            let lines: Vec<_> = logs.range(start..end).map(|l| self.log_line(l)).collect();
+            let title = match self.scroll {
+                0 => format!(" {name} "),
+                scroll => format!(" {name} ({scroll} more lines below) "),
+            };
+            frame.render_widget(
+                Paragraph::new(lines).block(Block::bordered().title(title)),
+                log_area,
+            );
+            footer_area
+        });
+
+        let footer = match (&self.input, &self.notice) {
+            (Some(input), _) => Line::from(format!("/{input}")),
+            (None, Some(notice)) => Line::from(notice.as_str()).bold(),
+            (None, None) => Line::from(HELP).dim(),
+        };
+        frame.render_widget(footer, footer_area);
+        if let Some(input) = &self.input {
+            frame.set_cursor_position((footer_area.x + input.len() as u16 + 1, footer_area.y));
+        }
+    }
+
+    fn log_line(&self, line: &LogLine) -> Line<'static> {
+        let text: String = line
+            .text
+            .replace('\t', "    ")
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect();
+        let style = match line.kind {
+            LogKind::Stdout => Style::new(),
+            LogKind::Stderr => Style::new().fg(Color::LightRed),
+            LogKind::Message => Style::new().fg(Color::Cyan),
+            LogKind::Error => Style::new().fg(Color::Red).bold(),
+        };
+        let is_match = self
+            .search
+            .as_ref()
+            .is_some_and(|s| text.to_lowercase().contains(&s.to_lowercase()));
+        let style = match is_match {
+            true => style.bg(Color::Yellow).fg(Color::Black),
+            false => style,
+        };
+        Line::styled(text, style)
+    }
+}
+
+fn status_style(status: &ServiceStatus) -> Style {
+    match status {
+        ServiceStatus::Ready | ServiceStatus::Completed => Style::new().fg(Color::Green),
+        ServiceStatus::Waiting | ServiceStatus::Initializing => Style::new().fg(Color::Yellow),
+        ServiceStatus::Running => Style::new(),
+        ServiceStatus::Stopping | ServiceStatus::Stopped => Style::new().dim(),
+        ServiceStatus::Failed(_) => Style::new().fg(Color::Red),
+    }
+}
+
+/// Reads terminal events on a thread of their own, until the receiver is dropped.
+fn read_events() -> UnboundedReceiver<Event> {
+    let (sender, receiver) = unbounded_channel();
+    std::thread::spawn(move || loop {
+        match event::poll(REFRESH_INTERVAL) {
+            Ok(true) => match event::read().map(|event| sender.send(event)) {
+                Ok(Ok(())) => {}
+                _ => break,
+            },
+            Ok(false) if !sender.is_closed() => {}
+            _ => break,
+        }
+    });
+    receiver
+}
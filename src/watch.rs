@@ -0,0 +1,90 @@
+use anyhow::Context;
+use glob::Pattern;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::timeout;
+
+/// How long files have to stay unchanged before a watched script or service is restarted.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The files matching any of the globs, except those matching an ignored glob.
+pub struct FileFilter {
+    include: Vec<Pattern>,
+    ignore: Vec<Pattern>,
+}
+
+impl FileFilter {
+    pub fn new(include: &[String], ignore: &[String]) -> anyhow::Result<Self> {
+        let patterns = |globs: &[String]| {
+            globs
+                .iter()
+                .map(|glob| Pattern::new(glob).with_context(|| format!("invalid glob '{glob}'")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            include: patterns(include)?,
+            ignore: patterns(ignore)?,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        self.include.iter().any(|p| p.matches_path(path))
+            && !self.ignore.iter().any(|p| p.matches_path(path))
+    }
+}
+
+/// Globs matching a directory and everything under it.
+pub fn whole_dir(dir: &Path) -> [String; 2] {
+    let dir = Pattern::escape(&dir.to_string_lossy());
+    [format!("{dir}/**"), dir]
+}
+
+/// Watches everything under `dir`. The paths that change are received until the watcher is
+/// dropped.
+pub fn watch_files(
+    dir: &Path,
+) -> anyhow::Result<(RecommendedWatcher, UnboundedReceiver<Vec<PathBuf>>)> {
+    let (mut watcher, changes) = file_watcher()?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .with_context(|| format!("watching {}", dir.display()))?;
+
+    Ok((watcher, changes))
+}
+
+/// A watcher that doesn't watch anything yet. The paths that change are received until it is
+/// dropped.
+pub fn file_watcher() -> anyhow::Result<(RecommendedWatcher, UnboundedReceiver<Vec<PathBuf>>)> {
+    let (tx, changes) = unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() {
+                let _ = tx.send(event.paths);
+            }
+        }
+    })
+    .context("creating file watcher")?;
+
+    Ok((watcher, changes))
+}
+
+/// Waits for a change to a file of the filter, then until the files settle down for `debounce`
+/// so that a burst of changes only restarts once.
+pub async fn next_change(
+    changes: &mut UnboundedReceiver<Vec<PathBuf>>,
+    filter: &FileFilter,
+    debounce: Duration,
+) {
+    loop {
+        match changes.recv().await {
+            Some(paths) if paths.iter().any(|path| filter.matches(path)) => break,
+            Some(_) => continue,
+            None => std::future::pending().await,
+        }
+    }
+
+    while let Ok(Some(_)) = timeout(debounce, changes.recv()).await {}
+}